@@ -29,7 +29,7 @@ async fn main() -> Result<()> {
 
         let assistant_msg = write_chat(&ollama, req).await?; // could be a union of response and final object.
         if let Some(assistant_msg) = assistant_msg {
-            msg_thread.push(assistant_msg)
+            msg_thread.push(assistant_msg);
         }
     }
     println!("{msg_thread:#?}");
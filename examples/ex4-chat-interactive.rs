@@ -21,14 +21,14 @@ async fn main() -> Result<()> {
         let mut user_msg = String::new();
         println!(">> Awaiting your message");
         let _ = stdin().read_line(&mut user_msg);
-        let user_msg = ChatMessage::new(MessageRole::User, user_msg.to_string());
+        let user_msg = ChatMessage::new(MessageRole::User, user_msg);
         msg_thread.push(user_msg);
         // Clone really necessary?
         let req = ChatMessageRequest::new(MODEL.to_string(), msg_thread.clone());
         println!("----Assistant----");
         let assistant_msg = write_chat(&ollama, req).await?; // could be a union of response and final object.
         if let Some(assistant_msg) = assistant_msg {
-            msg_thread.push(assistant_msg)
+            msg_thread.push(assistant_msg);
         }
     }
 }
@@ -51,7 +51,7 @@ pub async fn write_embeddings(
                 .file_stem()
                 .and_then(|s| s.to_str())
                 .expect("Failed to extract file stem");
-            let file_name = format!("{stem}_embeddings.json",);
+            let file_name = format!("{stem}_embeddings.json");
             let output_path = output_path.join(file_name);
             println!("Writing embeddings to {}", output_path.display());
             let _ = write_vec_to_json(&output_path, &embeddings);
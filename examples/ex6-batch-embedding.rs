@@ -1,5 +1,5 @@
 use anyhow::Result;
-use fastembed::{EmbeddingBase, EmbeddingModel, FlagEmbedding, InitOptions};
+use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
 use rag_rs::utils::{ensure_dir, write_vec_to_json};
 use std::{fs, path::Path};
 use text_splitter::TextSplitter;
@@ -22,13 +22,13 @@ pub fn main() -> Result<()> {
     let chunks: Vec<_> = splitter.chunks(&content, max_tokens).collect();
 
     // Embeddings
-    let model: FlagEmbedding = FlagEmbedding::try_new(InitOptions {
-        model_name: EmbeddingModel::BGEBaseEN,
-        show_download_message: true,
+    let model = TextEmbedding::try_new(InitOptions {
+        model_name: EmbeddingModel::BGEBaseENV15,
+        show_download_progress: true,
         ..Default::default()
     })?;
 
-    let embeddings = model.passage_embed(chunks, None)?;
+    let embeddings = model.embed(chunks, None)?;
 
     let output_path = embeddings_path.join("rust_book_embeddings.json");
     println!("Writing embeddings to {}", output_path.display());
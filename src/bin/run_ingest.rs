@@ -1,148 +1,296 @@
-use anyhow::{Context, Result};
-use arrow_array::{
-    types::Float32Type, FixedSizeListArray, Int32Array, RecordBatch, RecordBatchIterator,
-};
-use arrow_array::{ArrayRef, StringArray};
-use arrow_schema::{DataType, Field, Schema};
+use anyhow::Result;
+use chrono::Utc;
+use clap::Parser;
 use dotenv::dotenv;
-use fastembed::{EmbeddingModel, TextEmbedding};
-use lancedb::connection::CreateTableMode;
-use lancedb::{Connection, Table};
-use rag_rs::consts::{EMBEDDINGSIZE, MAX_TOKENS};
-use rag_rs::embed::{init_model, init_splitter};
-use rag_rs::utils::ensure_dir;
-use std::env;
-use std::sync::Arc;
-use std::{fs, path::Path};
-use tracing::{info, info_span};
+use rag_rs::chunk::init_chunker;
+use rag_rs::embed::{init_embedder, Embedder, EmbedderConfig};
+use rag_rs::embeddingsdb::{Config, IndexParams, Metric, VectorStore};
+use rag_rs::ingest::{
+    discover_documents, is_within, source_name, ChunkingConfig, ChunkingSettings, Document,
+    DocumentFilter, IngestSummary,
+};
+use rag_rs::record::{check_metadata_key, quote_literal, ChunkMetadata};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use tracing::{info, info_span, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-// TODO: ? Add more context to the DB? E.g. which page of the book.
-
 const DOCUMENT_PATH: &str = "./knowledge/2024-02-13_the_rust_book.txt";
-const TABLE_NAME: &str = "EmbeddingsTable";
+
+/// Chunk, embed and store documents in `LanceDB`.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Files or directories to ingest. Directories are walked recursively.
+    #[arg(default_value = DOCUMENT_PATH)]
+    paths: Vec<PathBuf>,
+    /// File extensions to pick up inside directories. Pass an empty string to accept all.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "txt,md,html,htm,pdf,epub,rs"
+    )]
+    extensions: Vec<String>,
+    /// Only ingest files matching one of these globs, e.g. `--include 'ch0*'`.
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip files matching one of these globs, e.g. `--exclude '**/drafts/**'`.
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Only embed new or changed chunks and drop documents that no longer exist, instead of
+    /// rebuilding the whole table. Stored documents within the given paths that are not part
+    /// of this run count as removed, documents elsewhere are kept.
+    #[arg(long)]
+    incremental: bool,
+    /// Number of chunks that are embedded and written to the table at once.
+    #[arg(long, default_value_t = 64)]
+    batch_size: usize,
+    /// Attach metadata to every chunk of this run, e.g. `--meta lang=en --meta tag=book`. Each
+    /// key becomes a column that `run_query --filter` can match. With `--incremental`,
+    /// unchanged documents keep their previous metadata.
+    #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    meta: Vec<(String, String)>,
+    /// Distance metric of the table, used by the vector index and by every search. New tables
+    /// use cosine if not set, `--incremental` keeps the metric of the table.
+    #[arg(long, env = "DISTANCE_METRIC", value_enum)]
+    metric: Option<Metric>,
+    #[command(flatten)]
+    chunking: ChunkingConfig,
+    #[command(flatten)]
+    embedder: EmbedderConfig,
+    #[command(flatten)]
+    index: IndexParams,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
+    let args = Args::parse();
     tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
     let span = info_span!("Main execution");
     let _enter = span.enter();
-    let document_path = Path::new(DOCUMENT_PATH);
-    let _ = ensure_dir(document_path);
-    let splitter = init_splitter()?;
-    let model = init_model()?;
+    let extra: BTreeMap<String, String> = args.meta.into_iter().collect();
+    let extensions: Vec<_> = args
+        .extensions
+        .into_iter()
+        .filter(|e| !e.is_empty())
+        .collect();
+    let filter = DocumentFilter::new(&extensions, &args.include, &args.exclude)?;
+    let documents = discover_documents(&args.paths, &filter)?;
+    anyhow::ensure!(
+        !documents.is_empty(),
+        "No documents found in {:?}",
+        args.paths
+    );
+    info!("Found {} documents", documents.len());
+    let roots = args
+        .paths
+        .iter()
+        .map(|path| source_name(path))
+        .collect::<Result<Vec<_>>>()?;
+    let embedder = init_embedder(&args.embedder).await?;
+    let chunker = init_chunker(&args.chunking, embedder.as_ref())?;
 
-    // Init LanceDB
-    //let default_conf = embeddingsdb::Config::default();
-    let db_uri = env::var("DATABASE_PATH").expect("Environment var DATABASE_PATH must be set");
-    let conn = lancedb::connect(&db_uri).execute().await?;
+    let config = Config::from_env()?;
+    let mut summary = IngestSummary::default();
+    let (mut store, mut stored_documents) = open_or_create_store(
+        &config,
+        embedder,
+        chunker.settings(),
+        args.incremental,
+        args.metric,
+        &mut summary,
+    )
+    .await?;
 
-    // Well ... this could be better ;)
-    let content = fs::read_to_string(document_path).context("Failed to read documents")?;
-    let chunks: Vec<_> = splitter
-        .chunks(&content, MAX_TOKENS)
-        .map(|text| format!("passage: {text}"))
-        .collect();
-    // Not happy with the clone. How expensive is a clone of a Vec<&str>?
-    info!("Creating embeddings");
-    let embeddings = model.embed(chunks.clone(), None)?;
-    assert_eq!(embeddings.len(), chunks.len());
-    assert_eq!(i32::try_from(embeddings[0].len()).unwrap(), EMBEDDINGSIZE);
-    let n_items = i32::try_from(chunks.len())
-        .expect("I don't expect number of vectors to be bigger than {i32::MAX}");
-    info!("Inserting embeddings");
+    let ingested_at = Utc::now();
+    let mut writer = BatchWriter::new(args.batch_size);
+    let n_documents = documents.len();
+    for (i, document_path) in documents.iter().enumerate() {
+        let document = match Document::read(document_path) {
+            Ok(document) => document,
+            Err(e) => {
+                warn!("Skipping {}: {e:#}", document_path.display());
+                summary.skipped += 1;
+                // Keep what an earlier run stored rather than removing it below
+                let source = source_name(document_path)
+                    .unwrap_or_else(|_| document_path.display().to_string());
+                if stored_documents.remove(&source).is_some() {
+                    let source_filter = format!("source = {}", quote_literal(&source));
+                    summary.kept += store.count(Some(&source_filter)).await?;
+                }
+                continue;
+            }
+        };
+        let source_filter = format!("source = {}", quote_literal(&document.source));
+        let mut stored_rows = 0;
+        let stored_embeddings = match stored_documents.remove(&document.source) {
+            Some(hash) if hash == document.hash => {
+                info!("Skipping unchanged {}", document.source);
+                summary.kept += store.count(Some(&source_filter)).await?;
+                continue;
+            }
+            Some(_) => {
+                // Rows are replaced as a whole, but unchanged chunks keep their embedding
+                let stored_embeddings = store.chunk_embeddings(&document.source).await?;
+                stored_rows = store.delete(&source_filter).await?;
+                stored_embeddings
+            }
+            None => HashMap::new(),
+        };
+        info!("[{}/{n_documents}] Splitting {}", i + 1, document.source);
+        let mut reused = 0;
+        for (text, mut metadata) in document.split(&chunker, ingested_at) {
+            metadata.extra.clone_from(&extra);
+            let embedding = stored_embeddings.get(&metadata.chunk_hash).cloned();
+            if embedding.is_some() {
+                reused += 1;
+            }
+            writer.push(&mut store, text, metadata, embedding).await?;
+        }
+        summary.kept += reused;
+        summary.removed += stored_rows.saturating_sub(reused);
+    }
+    writer.flush(&mut store).await?;
+    summary.added = writer.embedded;
 
-    // Create Schema
-    let schema = Arc::new(Schema::new(vec![
-        Field::new("id", DataType::Int32, false),
-        Field::new("text", DataType::Utf8, true),
-        Field::new(
-            "embedding",
-            DataType::FixedSizeList(
-                Arc::new(Field::new("item", DataType::Float32, true)),
-                EMBEDDINGSIZE,
-            ),
-            true,
-        ),
-    ]));
-    let tbl = create_or_overwrite_table(&conn, TABLE_NAME, schema.clone()).await?;
-    // Convert data to RecordBatch stream.
-    let batches = RecordBatchIterator::new(
-        vec![RecordBatch::try_new(
-            schema.clone(),
-            vec![
-                Arc::new(Int32Array::from_iter_values(1..=n_items)),
-                Arc::new(Arc::new(StringArray::from(chunks)) as ArrayRef),
-                Arc::new(
-                    FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
-                        embeddings
-                            .into_iter()
-                            .map(|inner_vec| Some(inner_vec.into_iter().map(|item| Some(item)))),
-                        EMBEDDINGSIZE,
-                    ),
-                ),
-            ],
-        )
-        .context("Creating RecordBatch failed")?]
-        .into_iter()
-        .map(Ok),
-        schema.clone(),
-    );
-    // Create Table
-    tbl.add(batches).execute().await?;
-    info!("Finished inserting embeddings");
+    summary.removed += remove_gone_documents(&store, stored_documents.keys(), &roots).await?;
+    if !store.has_fts_index() {
+        store.create_fts_index().await?;
+    }
+    store.update_index(&args.index).await?;
+    info!("Finished ingestion: {summary}");
     Ok(())
 }
 
-async fn create_or_overwrite_table(
-    conn: &Connection,
-    name: &str,
-    schema: Arc<Schema>,
-) -> Result<Table> {
-    info!("Creating empty table {name}.");
-    let table = conn
-        .create_empty_table(name, schema)
-        .mode(CreateTableMode::Overwrite)
-        .execute()
-        .await
-        .context("Failed to create empty table {name}")?;
-    Ok(table)
+/// Delete the `stored` documents within `roots` that this run didn't find anymore. Documents
+/// elsewhere were ingested from other paths and are kept. Returns the number of deleted rows.
+async fn remove_gone_documents(
+    store: &VectorStore,
+    stored: impl Iterator<Item = &String>,
+    roots: &[String],
+) -> Result<usize> {
+    let mut removed = 0;
+    for source in stored.filter(|source| roots.iter().any(|root| is_within(source, root))) {
+        info!("Removing {source}");
+        removed += store
+            .delete(&format!("source = {}", quote_literal(source)))
+            .await?;
+    }
+    Ok(removed)
 }
 
-pub fn get_embedding_size(model: EmbeddingModel) -> Option<usize> {
-    TextEmbedding::list_supported_models()
-        .iter()
-        .find(|info| info.model == model)
-        .map(|info| info.dim)
+/// For incremental runs, open the table and read which documents it holds. Otherwise replace
+/// it with an empty one.
+async fn open_or_create_store(
+    config: &Config,
+    embedder: Box<dyn Embedder>,
+    chunking: &ChunkingSettings,
+    incremental: bool,
+    metric: Option<Metric>,
+    summary: &mut IngestSummary,
+) -> Result<(VectorStore, HashMap<String, String>)> {
+    let existing_rows = VectorStore::row_count(config).await?;
+    if incremental && existing_rows.is_some() {
+        let store = VectorStore::open(config, embedder).await?;
+        anyhow::ensure!(
+            store.settings().chunking == *chunking,
+            "The table was chunked with {:?}, but this run uses {chunking:?}. Re-ingest without \
+             --incremental to rebuild it.",
+            store.settings().chunking
+        );
+        if let Some(metric) = metric {
+            anyhow::ensure!(
+                store.settings().metric == metric,
+                "The table uses the {} metric, but this run uses {}. Re-ingest without \
+                 --incremental to rebuild it.",
+                store.settings().metric.name(),
+                metric.name()
+            );
+        }
+        let stored_documents = store.document_hashes().await?;
+        Ok((store, stored_documents))
+    } else {
+        summary.removed = existing_rows.unwrap_or_default();
+        let metric = metric.unwrap_or_default();
+        let store = VectorStore::create(config, embedder, chunking.clone(), metric).await?;
+        Ok((store, HashMap::new()))
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::create_or_overwrite_table;
-    use std::fs;
-    use std::path::Path;
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected KEY=VALUE, got '{s}'"))?;
+    check_metadata_key(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
 
-    const DB_URI: &str = ".test_data/test_db";
-    const TABLE_NAME: &str = "test_table";
+/// Buffers chunks until a batch is full, then hands them to the store, which embeds the ones
+/// without an embedding. Memory use is bounded by the batch size, not by the corpus size.
+struct BatchWriter {
+    batch_size: usize,
+    texts: Vec<String>,
+    metadata: Vec<ChunkMetadata>,
+    embeddings: Vec<Option<Vec<f32>>>,
+    /// Rows appended to the table so far.
+    written: usize,
+    /// Chunks that had to be embedded so far.
+    embedded: usize,
+}
+
+impl BatchWriter {
+    fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        BatchWriter {
+            batch_size,
+            texts: Vec::with_capacity(batch_size),
+            metadata: Vec::with_capacity(batch_size),
+            embeddings: Vec::with_capacity(batch_size),
+            written: 0,
+            embedded: 0,
+        }
+    }
+
+    /// Queue a chunk, `embedding` is reused if present and computed otherwise.
+    async fn push(
+        &mut self,
+        store: &mut VectorStore,
+        text: String,
+        metadata: ChunkMetadata,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<()> {
+        self.texts.push(text);
+        self.metadata.push(metadata);
+        self.embeddings.push(embedding);
+        if self.texts.len() >= self.batch_size {
+            self.flush(store).await?;
+        }
+        Ok(())
+    }
 
-    #[tokio::test]
-    async fn should_create_table_if_not_exists() {
-        let _ = fs::remove_dir_all(DB_URI);
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("id", DataType::Int32, false),
-            Field::new("item", DataType::Utf8, true),
-        ]));
-        let conn = lancedb::connect(DB_URI).execute().await.unwrap();
-        let _ = create_or_overwrite_table(&conn, TABLE_NAME, schema)
-            .await
-            .unwrap();
-        let db_path = Path::new(DB_URI);
-        assert!(db_path.exists());
-        let _ = fs::remove_dir_all(DB_URI);
+    async fn flush(&mut self, store: &mut VectorStore) -> Result<()> {
+        if self.texts.is_empty() {
+            return Ok(());
+        }
+        let n_rows = self.texts.len();
+        self.embedded += self.embeddings.iter().filter(|e| e.is_none()).count();
+        store
+            .add_with_embeddings(
+                std::mem::take(&mut self.texts),
+                std::mem::take(&mut self.metadata),
+                std::mem::take(&mut self.embeddings),
+            )
+            .await?;
+        self.written += n_rows;
+        info!(
+            "Wrote {} rows ({} embedded, {} reused)",
+            self.written,
+            self.embedded,
+            self.written - self.embedded
+        );
+        Ok(())
     }
 }
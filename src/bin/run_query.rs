@@ -1,25 +1,47 @@
-use anyhow::{Context, Result};
-use arrow_array::StringArray;
-use async_openai::{
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
+use anyhow::Result;
+use clap::Parser;
 use dotenv::dotenv;
-use fastembed::TextEmbedding;
-use futures::TryStreamExt;
-use lancedb::{query::ExecutableQuery, Table};
-use rag_rs::embed::init_model;
-use std::{env, io::stdin};
-use tracing::{debug, info};
+use futures::StreamExt;
+use rag_rs::cite::{format_context, number_sources, CitedAnswer, Source, CITATION_INSTRUCTIONS};
+use rag_rs::condense::{plan_query, QueryPlan};
+use rag_rs::embed::{init_embedder, EmbedderConfig};
+use rag_rs::embeddingsdb::{Config, SearchHit, SearchParams, VectorStore};
+use rag_rs::filter::Filter;
+use rag_rs::history::{ChatHistory, HistoryConfig, TokenCounter};
+use rag_rs::llm::{init_llm, LlmConfig, TokenStream};
+use rag_rs::rank::{diversify, MmrConfig};
+use rag_rs::record::stitch_adjacent;
+use rag_rs::rerank::{init_reranker, rerank, Reranker, RerankerConfig};
+use std::io::stdin;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-const TABLE_NAME: &str = "EmbeddingsTable";
+/// Chat with an LLM about the ingested documents.
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(flatten)]
+    embedder: EmbedderConfig,
+    #[command(flatten)]
+    search: SearchParams,
+    #[command(flatten)]
+    reranker: RerankerConfig,
+    #[command(flatten)]
+    mmr: MmrConfig,
+    #[command(flatten)]
+    llm: LlmConfig,
+    #[command(flatten)]
+    history: HistoryConfig,
+    /// Search for the question as typed instead of letting the LLM rewrite follow-up
+    /// questions into standalone queries and skip retrieval for questions about the chat.
+    #[arg(long, env = "NO_QUERY_REWRITE")]
+    no_query_rewrite: bool,
+    /// Only search chunks matching all conditions, e.g. `--filter 'source=ch08* lang=en'`.
+    /// Change it while chatting with `/filter <conditions>`, clear it with `/filter`.
+    #[arg(long)]
+    filter: Option<Filter>,
+}
 
-// TODO: Cannot answer "what was my last question correctly"
 // TODO: Write traces to file not stdout
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -33,109 +55,209 @@ async fn main() -> Result<()> {
         .init();
 
     dotenv().ok();
-    let model = init_model()?;
+    let args = Args::parse();
+    let embedder = init_embedder(&args.embedder).await?;
+    let counter = TokenCounter::load(
+        args.history.chat_tokenizer.as_deref(),
+        embedder.tokenizer_file().as_deref(),
+    )?;
+    let reranker = init_reranker(&args.reranker, &args.embedder.cache)?;
 
-    let db_uri = env::var("DATABASE_PATH").expect("Environment var DATABASE_PATH must be set");
-    let conn = lancedb::connect(&db_uri).execute().await?;
-    let tbl = conn.open_table(TABLE_NAME).execute().await?;
+    let store = VectorStore::open(&Config::from_env()?, embedder).await?;
+    info!(
+        "Searching with the {} metric of the table",
+        store.settings().metric.name()
+    );
+    let mut search = args.search.clone();
+    if let Some(filter) = &args.filter {
+        search.filter = filter.to_sql(store.schema())?;
+    }
 
-    let local_conf = OpenAIConfig::new()
-        .with_api_key("sk-no-key-required")
-        .with_api_base("http://localhost:8080/v1");
-    let client = Client::with_config(local_conf);
+    let llm = init_llm(&args.llm)?;
 
-    let system = ChatCompletionRequestSystemMessageArgs::default()
-                .content("Use the provided CONTEXT to answer questions. Documents in the CONTEXT are delimited with triple ~, i.e. `~~~`. If the answer cannot be found in the CONTEXT, write 'I could not find an answer.'")
-                .build()?;
+    let system = format!("Use the provided CONTEXT to answer questions. Documents in the CONTEXT are delimited with triple ~, i.e. `~~~`. {CITATION_INSTRUCTIONS} If the answer cannot be found in the CONTEXT, write 'I could not find an answer.'");
 
-    let mut msg_thread: Vec<_> = vec![system.into()];
+    let mut history = ChatHistory::for_model(system, counter, llm.as_ref(), &args.history).await?;
 
     loop {
         // Read user message from stdin
         println!(">> Awaiting your message");
         let mut query = String::new();
         let _ = stdin().read_line(&mut query);
+        if let Some(filter) = filter_command(&query) {
+            match filter.and_then(|filter| Ok((filter.to_sql(store.schema())?, filter))) {
+                Ok((sql, filter)) => {
+                    search.filter = sql;
+                    println!("Filter set to '{filter}'");
+                }
+                Err(e) => println!("Invalid filter: {e:#}"),
+            }
+            continue;
+        }
 
-        // Retrieve neighbors
-        let nn_chunks = get_nearest_neighbor_chunks(&query, &model, &tbl).await?;
-        let context = format!("```{}```", nn_chunks[..2].join("```"));
+        let plan = if args.no_query_rewrite {
+            QueryPlan::Retrieve {
+                query: query.trim().to_string(),
+            }
+        } else {
+            plan_query(llm.as_ref(), history.turns(), &query).await?
+        };
+        let (sources, user_msg) =
+            user_message(plan, &query, &store, &search, reranker.as_deref(), &args).await?;
 
-        let user_msg = ChatCompletionRequestUserMessageArgs::default()
-            .content(format!(
-                "QUESTION: {query}\n
-            CONTEXT: {context}"
-            ))
-            .build()?;
+        // Older turns go first if the prompt outgrows the context window. A longer summary can
+        // push out more turns, which are summarized as well.
+        loop {
+            let dropped = history.fit(&user_msg)?;
+            if !args.history.summarize_history || dropped.is_empty() {
+                break;
+            }
+            history.summarize(llm.as_ref(), &dropped).await?;
+        }
+        let messages = history.prompt(&user_msg);
 
-        msg_thread.push(user_msg.into());
-
-        let request = CreateChatCompletionRequestArgs::default()
-            .n(1)
-            .messages(msg_thread.clone())
-            .build()
-            .context("Failed to build ChatCompletionRequest")?;
-
-        info!("{}", serde_json::to_string(&request).unwrap());
-        let response = &client
-            .chat()
-            .create(request)
-            .await
-            .context("Failed to create CompletionResponse")?;
+        info!("{messages:?}");
+        let stream = llm.chat_stream(&messages).await?;
 
         println!("\nResponse:\n");
-        let response_text = response
-            .choices
-            .first()
-            .as_ref()
-            .unwrap()
-            .message
-            .content
-            .as_ref()
-            .unwrap()
-            .clone();
-        print!("{response_text}");
+        let response_text = write_stream(stream).await?;
+        let answer = CitedAnswer::resolve(&response_text, &sources);
+        if !answer.unknown.is_empty() {
+            print!(
+                "\n\n(Ignoring citations of unknown sources {:?})",
+                answer.unknown
+            );
+        }
+        let footer = answer.footer();
+        if !footer.is_empty() {
+            print!("\n\n{footer}");
+        }
         print!("\n\n");
-        let assistant_response = ChatCompletionRequestAssistantMessageArgs::default()
-            .content(response_text)
-            .build()?;
-        msg_thread.push(assistant_response.into());
+        history.push(query.trim(), answer.text);
     }
 }
 
+/// The sources retrieved for `plan` and the message asking `question` with them.
+async fn user_message(
+    plan: QueryPlan,
+    question: &str,
+    store: &VectorStore,
+    search: &SearchParams,
+    reranker: Option<&dyn Reranker>,
+    args: &Args,
+) -> Result<(Vec<Source>, String)> {
+    let question = question.trim();
+    match plan {
+        QueryPlan::Retrieve { query } => {
+            // Retrieve neighbors
+            let nn_chunks = get_nearest_neighbor_chunks(
+                &query,
+                store,
+                search,
+                reranker,
+                args.reranker.rerank_fetch_factor,
+                &args.mmr,
+            )
+            .await?;
+            log_hits(&nn_chunks);
+            let sources = retrieved_sources(&nn_chunks);
+            let context = format_context(&sources);
+            let user_msg = format!(
+                "QUESTION: {question}\n
+            CONTEXT: {context}"
+            );
+            Ok((sources, user_msg))
+        }
+        QueryPlan::Conversation => Ok((
+            Vec::new(),
+            format!(
+                "QUESTION: {question}\n
+            This question is about our conversation, there is no CONTEXT. Answer it from the \
+                 conversation so far."
+            ),
+        )),
+    }
+}
+
+/// Retrieve `params.k` chunks. With a reranker, `fetch_factor` times as many candidates are
+/// retrieved and the reranker picks the best of them. With MMR, the reranker keeps
+/// `mmr.mmr_fetch_k` candidates and MMR picks `params.k` diverse chunks among them.
 async fn get_nearest_neighbor_chunks(
     query: &str,
-    model: &TextEmbedding,
-    table: &Table,
-) -> Result<Vec<String>> {
-    // TODO: I might wrap LanceDB and the EmbeddingModel into one VectorStore and implement this as
-    // a function on this new type.
-    let query_embedding = model
-        .embed(vec![query], None)?
-        .pop()
-        .expect("Outer Vec will contain one inner vec");
-    let nearest_neighbors = table
-        .query()
-        .nearest_to(query_embedding)
-        .context("Probably cannot convert input vector")?
-        .execute()
-        .await?
-        .try_collect::<Vec<_>>()
-        .await?;
-    assert_eq!(
-        1,
-        nearest_neighbors.len(),
-        "Pretty sure this will always be one ... but not certain"
-    );
-    let nn = &nearest_neighbors[0];
-    let nn_chunks = nn
-        .column_by_name("text")
-        .unwrap()
-        .as_any()
-        .downcast_ref::<StringArray>()
-        .unwrap()
-        .iter()
-        .flatten()
-        .map(|s| s.to_string())
-        .collect::<Vec<String>>();
-    Ok(nn_chunks)
+    store: &VectorStore,
+    params: &SearchParams,
+    reranker: Option<&dyn Reranker>,
+    fetch_factor: usize,
+    mmr: &MmrConfig,
+) -> Result<Vec<SearchHit>> {
+    let k = match mmr.mmr_lambda {
+        Some(_) => mmr.mmr_fetch_k.max(params.k),
+        None => params.k,
+    };
+    let candidates = SearchParams {
+        k: match reranker {
+            Some(_) => k * fetch_factor.max(1),
+            None => k,
+        },
+        ..params.clone()
+    };
+    let mut hits = store.search(query, &candidates).await?;
+    if let Some(reranker) = reranker {
+        hits = rerank(reranker, query, hits, k).await?;
+    }
+    if let Some(lambda) = mmr.mmr_lambda {
+        hits = diversify(hits, lambda, params.k);
+    }
+    Ok(hits)
+}
+
+fn log_hits(hits: &[SearchHit]) {
+    for hit in hits {
+        let meta = &hit.chunk.metadata;
+        info!(
+            "Using chunk {} of {} (chars {}..{}, score {}, similarity {}, keyword score {:?}, \
+             rerank score {:?})",
+            meta.chunk_index,
+            meta.source,
+            meta.char_start,
+            meta.char_end,
+            hit.score,
+            hit.similarity,
+            hit.keyword_score,
+            hit.rerank_score
+        );
+    }
+}
+
+/// Print the reply while it is generated and return all of it.
+async fn write_stream(mut stream: TokenStream) -> Result<String> {
+    let mut stdout = tokio::io::stdout();
+    let mut reply = String::new();
+    while let Some(token) = stream.next().await {
+        let token = token?;
+        stdout.write_all(token.as_bytes()).await?;
+        stdout.flush().await?;
+        reply.push_str(&token);
+    }
+    Ok(reply)
+}
+
+/// Parse a `/filter <conditions>` line, `None` if the line is a regular message.
+fn filter_command(line: &str) -> Option<Result<Filter>> {
+    let rest = line.trim().strip_prefix("/filter")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest.parse())
+}
+
+/// Number the retrieved chunks for citing them in the prompt.
+fn retrieved_sources(hits: &[SearchHit]) -> Vec<Source> {
+    if hits.is_empty() {
+        info!("No matching chunks found");
+    }
+    // Neighbouring chunks repeat the text they overlap in, so join them first
+    number_sources(stitch_adjacent(
+        hits.iter().map(|hit| hit.chunk.clone()).collect(),
+    ))
 }
@@ -0,0 +1,839 @@
+use crate::consts::MAX_TOKENS;
+use crate::embed::Embedder;
+use crate::ingest::{ChunkingConfig, ChunkingSettings};
+use anyhow::{anyhow, Context, Result};
+use std::cmp::Ordering;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use text_splitter::{ChunkSizer, TextSplitter};
+use tokenizers::Tokenizer;
+use tracing::{info, instrument, warn};
+
+/// Joins the headings of a heading path, e.g. `Ch. 8 > Storing Keys with Hash Maps`.
+pub const HEADING_SEPARATOR: &str = " > ";
+
+/// Rust items that start a code section.
+const ITEM_KEYWORDS: [&str; 11] = [
+    "fn",
+    "impl",
+    "mod",
+    "struct",
+    "enum",
+    "trait",
+    "type",
+    "const",
+    "static",
+    "union",
+    "macro_rules!",
+];
+
+/// Modifiers that may precede the keyword of an item.
+const ITEM_MODIFIERS: [&str; 6] = ["pub", "async", "unsafe", "const", "default", "extern"];
+
+/// How documents are cut into chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChunkStrategy {
+    /// Markdown for `.md` files, code for `.rs` files and plain text for everything else.
+    #[default]
+    Auto,
+    /// Split on paragraphs, lines and sentences.
+    Text,
+    /// Split on headings and never inside fenced code blocks or lists.
+    Markdown,
+    /// Split Rust sources on item boundaries such as `fn`, `impl` and `mod`.
+    Code,
+}
+
+impl ChunkStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChunkStrategy::Auto => "auto",
+            ChunkStrategy::Text => "text",
+            ChunkStrategy::Markdown => "markdown",
+            ChunkStrategy::Code => "code",
+        }
+    }
+
+    /// Resolve [`ChunkStrategy::Auto`] by the extension of `source`.
+    #[must_use]
+    pub fn for_source(self, source: &str) -> ChunkStrategy {
+        if self != ChunkStrategy::Auto {
+            return self;
+        }
+        let extension = Path::new(source)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            // HTML and EPUB are converted to Markdown when loaded
+            Some("md" | "markdown" | "html" | "htm" | "xhtml" | "epub") => ChunkStrategy::Markdown,
+            Some("rs") => ChunkStrategy::Code,
+            _ => ChunkStrategy::Text,
+        }
+    }
+}
+
+/// Part of a document that no chunk crosses, e.g. a Markdown section or a Rust item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// Byte range in the document.
+    pub range: Range<usize>,
+    /// Headings or item signatures enclosing the section, outermost first.
+    pub heading_path: Vec<String>,
+    /// Byte ranges within the section that are only split if they don't fit into a chunk on
+    /// their own, e.g. fenced code blocks or nested items.
+    pub blocks: Vec<Range<usize>>,
+}
+
+/// Cut `text` into sections according to `strategy`, which must not be `Auto`.
+pub fn sections(text: &str, strategy: ChunkStrategy) -> Vec<Section> {
+    match strategy {
+        ChunkStrategy::Markdown => markdown_sections(text),
+        ChunkStrategy::Code => code_sections(text),
+        ChunkStrategy::Auto | ChunkStrategy::Text => vec![Section {
+            range: 0..text.len(),
+            heading_path: Vec::new(),
+            blocks: Vec::new(),
+        }],
+    }
+}
+
+/// A chunk of a document as produced by [`Chunker::chunks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSpan {
+    /// Byte range in the document, overlap included.
+    pub range: Range<usize>,
+    /// Headings or item signatures enclosing the chunk, outermost first.
+    pub heading_path: Vec<String>,
+    /// Number of bytes at the start of the chunk that repeat the end of the previous chunk.
+    pub overlap: usize,
+}
+
+/// Splits documents into chunks of `min_tokens` to `max_tokens` tokens that respect their
+/// structure. Consecutive chunks of a section share up to `overlap_tokens` tokens.
+pub struct Chunker {
+    splitter: TextSplitter<Tokenizer>,
+    tokenizer: Tokenizer,
+    settings: ChunkingSettings,
+}
+
+/// Create a chunker that measures chunks with the tokenizer of `embedder`, or the one in
+/// `config`, and fills in the chunk sizes `config` leaves open. Fails if chunks may exceed the
+/// maximum sequence length of the model, unless truncation is allowed.
+#[instrument(skip(embedder))]
+pub fn init_chunker(config: &ChunkingConfig, embedder: &dyn Embedder) -> Result<Chunker> {
+    let (tokenizer_id, path) = if let Some(path) = &config.tokenizer {
+        (path.display().to_string(), path.clone())
+    } else {
+        let path = embedder.tokenizer_file().ok_or_else(|| {
+            anyhow!(
+                "No local tokenizer for {}, pass the path of its tokenizer.json with --tokenizer",
+                embedder.model_id()
+            )
+        })?;
+        (embedder.model_id().to_string(), path)
+    };
+    let tokenizer = load_tokenizer(&path)?;
+
+    // The embedder adds special tokens like [CLS] and its document prefix to every chunk
+    let overhead = tokenizer
+        .encode(embedder.prefix_scheme().document_prefix(), true)
+        .map_err(|e| anyhow!("Failed to tokenize the document prefix: {e}"))?
+        .len();
+    let limit = embedder
+        .max_input_tokens()
+        .map(|max| max.saturating_sub(overhead));
+    let max_tokens = config.max_tokens.or(limit).unwrap_or(MAX_TOKENS);
+    match limit {
+        Some(limit) if max_tokens > limit => {
+            let message = format!(
+                "Chunks of up to {max_tokens} tokens exceed the {limit} tokens {} embeds, \
+                 the rest of longer chunks is ignored",
+                embedder.model_id()
+            );
+            anyhow::ensure!(
+                config.allow_truncation,
+                "{message}. Lower --max-tokens or pass --allow-truncation."
+            );
+            warn!("{message}");
+        }
+        Some(_) => {}
+        None => warn!(
+            "Maximum sequence length of {} is unknown, chunks of {max_tokens} tokens may get \
+             truncated",
+            embedder.model_id()
+        ),
+    }
+    let settings = ChunkingSettings {
+        tokenizer: tokenizer_id,
+        min_tokens: config.min_tokens.unwrap_or(max_tokens / 2),
+        max_tokens,
+        overlap_tokens: config.overlap_tokens.unwrap_or(max_tokens / 10),
+        strategy: config.strategy,
+    };
+    settings.validate()?;
+    info!("Chunking with {settings:?}");
+    Ok(Chunker::new(tokenizer, settings))
+}
+
+/// Load a `tokenizer.json`, given directly or as the directory containing it. Truncation and
+/// padding are turned off, they would distort the chunk sizes.
+pub fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
+    let file = if path.is_dir() {
+        path.join("tokenizer.json")
+    } else {
+        path.to_path_buf()
+    };
+    let mut tokenizer = Tokenizer::from_file(&file)
+        .map_err(|e| anyhow!("{e}"))
+        .with_context(|| format!("Failed to load tokenizer {}", file.display()))?;
+    tokenizer
+        .with_truncation(None)
+        .map_err(|e| anyhow!("{e}"))?
+        .with_padding(None);
+    Ok(tokenizer)
+}
+
+impl Chunker {
+    pub fn new(tokenizer: Tokenizer, settings: ChunkingSettings) -> Self {
+        Chunker {
+            splitter: TextSplitter::new(tokenizer.clone()).with_trim_chunks(true),
+            tokenizer,
+            settings,
+        }
+    }
+
+    /// The settings chunks are split with, to be stored with the table.
+    pub fn settings(&self) -> &ChunkingSettings {
+        &self.settings
+    }
+
+    /// The chunks of `text` in document order. `source` picks the strategy if it is
+    /// [`ChunkStrategy::Auto`].
+    pub fn chunks(&self, text: &str, source: &str) -> Vec<ChunkSpan> {
+        let mut chunks = Vec::new();
+        for section in sections(text, self.settings.strategy.for_source(source)) {
+            let mut ranges = Vec::new();
+            let mut current: Option<Range<usize>> = None;
+            for block in partition(&section.range, &section.blocks) {
+                // Pieces of a block that had to be split already have a size within the range,
+                // so only the first one may join the previous block. Blocks are only merged
+                // until the chunk reaches the minimum size to keep them apart where possible.
+                for (i, piece) in self.split_block(text, block).into_iter().enumerate() {
+                    current = match current {
+                        Some(chunk)
+                            if i == 0
+                                && self.size(&text[chunk.clone()]).is_lt()
+                                && self.size(&text[chunk.start..piece.end]).is_le() =>
+                        {
+                            Some(chunk.start..piece.end)
+                        }
+                        Some(chunk) => {
+                            ranges.push(chunk);
+                            Some(piece)
+                        }
+                        None => Some(piece),
+                    };
+                }
+            }
+            ranges.extend(current);
+            let mut previous: Option<Range<usize>> = None;
+            for range in ranges {
+                let (start, overlap) = match previous {
+                    Some(previous) => {
+                        let overlap = self.overlap(text, previous.clone());
+                        (previous.end - overlap, overlap)
+                    }
+                    None => (range.start, 0),
+                };
+                chunks.push(ChunkSpan {
+                    range: start..range.end,
+                    heading_path: section.heading_path.clone(),
+                    overlap,
+                });
+                previous = Some(range);
+            }
+        }
+        chunks
+    }
+
+    /// Length in bytes of the last `overlap_tokens` tokens of the chunk at `previous`, moved
+    /// forward to the next word boundary. Never the whole chunk.
+    fn overlap(&self, text: &str, previous: Range<usize>) -> usize {
+        if self.settings.overlap_tokens == 0 {
+            return 0;
+        }
+        let previous = &text[previous];
+        let Ok(encoding) = self.tokenizer.encode(previous, false) else {
+            return 0;
+        };
+        let offsets = encoding.get_offsets();
+        let first = offsets
+            .len()
+            .saturating_sub(self.settings.overlap_tokens)
+            .max(1);
+        let Some(&(mut start, _)) = offsets.get(first) else {
+            return 0;
+        };
+        if !previous[..start].ends_with(char::is_whitespace) {
+            match previous[start..].find(char::is_whitespace) {
+                Some(offset) => start += offset,
+                None => return 0,
+            }
+        }
+        previous[start..].trim_start().len()
+    }
+
+    fn split_block(&self, text: &str, block: Range<usize>) -> Vec<Range<usize>> {
+        let block = trim(text, block);
+        if block.is_empty() {
+            return Vec::new();
+        }
+        if self.size(&text[block.clone()]).is_le() {
+            return vec![block];
+        }
+        self.splitter
+            .chunk_indices(&text[block.clone()], self.capacity())
+            .map(|(start, piece)| block.start + start..block.start + start + piece.len())
+            .collect()
+    }
+
+    /// Size of a chunk before the overlap is added, so that chunks with overlap still fit into
+    /// `max_tokens`.
+    fn capacity(&self) -> RangeInclusive<usize> {
+        let settings = &self.settings;
+        let max = settings
+            .max_tokens
+            .saturating_sub(settings.overlap_tokens)
+            .max(1);
+        settings.min_tokens.min(max)..=max
+    }
+
+    /// Whether `text` is below, within or above [`Chunker::capacity`].
+    fn size(&self, text: &str) -> Ordering {
+        self.tokenizer.chunk_size(text, &self.capacity()).fits()
+    }
+}
+
+/// The blocks of a section plus the gaps between them, in order.
+fn partition(range: &Range<usize>, blocks: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut position = range.start;
+    for block in blocks {
+        // Blocks never reach beyond their section, even if the item end was misjudged
+        let block = block.start.max(position)..block.end.min(range.end);
+        if block.is_empty() {
+            continue;
+        }
+        if block.start > position {
+            parts.push(position..block.start);
+        }
+        position = block.end;
+        parts.push(block);
+    }
+    if position < range.end {
+        parts.push(position..range.end);
+    }
+    parts
+}
+
+/// Shrink `range` so that the text it covers has no leading or trailing whitespace.
+fn trim(text: &str, range: Range<usize>) -> Range<usize> {
+    let slice = &text[range.clone()];
+    let start = range.start + (slice.len() - slice.trim_start().len());
+    let end = range.start + slice.trim_end().len();
+    start..end.max(start)
+}
+
+/// Lines of `text` with their byte range, line breaks included.
+fn lines(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut start = 0;
+    text.split_inclusive('\n')
+        .map(|line| {
+            let range = start..start + line.len();
+            start = range.end;
+            (range, line)
+        })
+        .collect()
+}
+
+/// One section per ATX heading, i.e. `## Title`. Text before the first heading forms a section
+/// without heading path, a heading directly followed by a subheading joins its section.
+fn markdown_sections(text: &str) -> Vec<Section> {
+    let lines = lines(text);
+    let mut sections: Vec<Section> = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut current = Section {
+        range: 0..0,
+        heading_path: Vec::new(),
+        blocks: Vec::new(),
+    };
+    // Whether the current section has more than its heading
+    let mut has_body = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let (range, line) = &lines[i];
+        if let Some(fence) = fence_marker(line) {
+            let end = lines[i + 1..]
+                .iter()
+                .position(|(_, line)| line.trim_start().starts_with(fence))
+                .map_or(lines.len() - 1, |offset| i + 1 + offset);
+            current.blocks.push(range.start..lines[end].0.end);
+            has_body = true;
+            i = end + 1;
+            continue;
+        }
+        if let Some((level, title)) = atx_heading(line) {
+            if has_body {
+                current.range.end = range.start;
+                sections.push(current);
+                current = Section {
+                    range: range.start..range.start,
+                    heading_path: Vec::new(),
+                    blocks: Vec::new(),
+                };
+            }
+            while headings.last().is_some_and(|(l, _)| *l >= level) {
+                headings.pop();
+            }
+            headings.push((level, title));
+            current.heading_path = headings.iter().map(|(_, title)| title.clone()).collect();
+            has_body = false;
+            i += 1;
+            continue;
+        }
+        if is_list_item(line) {
+            let end = list_end(&lines, i);
+            current.blocks.push(range.start..lines[end].0.end);
+            has_body = true;
+            i = end + 1;
+            continue;
+        }
+        has_body |= !line.trim().is_empty();
+        i += 1;
+    }
+    current.range.end = text.len();
+    sections.push(current);
+    sections.retain(|section| !text[section.range.clone()].trim().is_empty());
+    sections
+}
+
+/// The characters a fenced code block opened by `line` has to be closed with.
+fn fence_marker(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    ["```", "~~~"]
+        .into_iter()
+        .find(|fence| line.starts_with(fence))
+}
+
+/// Level and title of an ATX heading like `## Storing Keys`.
+fn atx_heading(line: &str) -> Option<(usize, String)> {
+    let line = line.trim_end();
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let line = &line[indent..];
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    if indent > 3 || !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim_end();
+    Some((level, title.to_string()))
+}
+
+fn is_list_item(line: &str) -> bool {
+    let line = line.trim_start();
+    if ["- ", "* ", "+ "]
+        .iter()
+        .any(|marker| line.starts_with(marker))
+    {
+        return true;
+    }
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    digits > 0 && (line[digits..].starts_with(". ") || line[digits..].starts_with(") "))
+}
+
+/// Index of the last line of the list starting at line `start`. Items, indented continuation
+/// lines and blank lines between them belong to the list.
+fn list_end(lines: &[(Range<usize>, &str)], start: usize) -> usize {
+    let mut end = start;
+    for (i, (_, line)) in lines.iter().enumerate().skip(start + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let continues = is_list_item(line) || line.starts_with([' ', '\t']);
+        if !continues || fence_marker(line).is_some() || atx_heading(line).is_some() {
+            break;
+        }
+        end = i;
+    }
+    end
+}
+
+/// One section per top-level item, named by its signature. Items nested in `impl`, `trait` or
+/// `mod` blocks become blocks of their section. Doc comments and attributes stay with their
+/// item, anything before the first item forms a section without heading path.
+fn code_sections(text: &str) -> Vec<Section> {
+    let lines = lines(text);
+    let mut sections: Vec<Section> = Vec::new();
+    let mut current = Section {
+        range: 0..0,
+        heading_path: Vec::new(),
+        blocks: Vec::new(),
+    };
+    // Last line of the most recent nested item
+    let mut nested_end = 0;
+    for (i, (_, line)) in lines.iter().enumerate() {
+        let Some(signature) = item_signature(line) else {
+            continue;
+        };
+        let start = item_start(&lines, i);
+        if indent(line) == 0 {
+            current.range.end = start;
+            sections.push(current);
+            current = Section {
+                range: start..start,
+                heading_path: vec![signature],
+                blocks: Vec::new(),
+            };
+        } else if !current.heading_path.is_empty() && (current.blocks.is_empty() || i > nested_end)
+        {
+            nested_end = item_end(&lines, i);
+            current.blocks.push(start..lines[nested_end].0.end);
+        }
+    }
+    current.range.end = text.len();
+    sections.push(current);
+    sections.retain(|section| !text[section.range.clone()].trim().is_empty());
+    sections
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Signature of the item declared on `line`, cut before its body, e.g. `impl Chunker`.
+fn item_signature(line: &str) -> Option<String> {
+    let trimmed = line.trim();
+    let mut words = trimmed.split_whitespace().peekable();
+    while let Some(word) = words.peek() {
+        let is_modifier = ITEM_MODIFIERS.contains(word)
+            || word.starts_with("pub(")
+            || word.starts_with('"') && word.ends_with('"');
+        // `const fn` is a function, `const X` a constant
+        if !is_modifier || *word == "const" && !trimmed.contains("const fn") {
+            break;
+        }
+        words.next();
+    }
+    let keyword = words.next()?;
+    if !ITEM_KEYWORDS
+        .iter()
+        .any(|item| keyword == *item || keyword.starts_with(&format!("{item}<")))
+    {
+        return None;
+    }
+    let end = [" {", ";", " =", " where"]
+        .iter()
+        .filter_map(|delimiter| trimmed.find(delimiter))
+        .min()
+        .unwrap_or(trimmed.len());
+    Some(trimmed[..end].to_string())
+}
+
+/// First line of the doc comments and attributes directly above the item on line `item`.
+fn item_start(lines: &[(Range<usize>, &str)], item: usize) -> usize {
+    let mut start = item;
+    while start > 0 {
+        let line = lines[start - 1].1.trim_start();
+        if !(line.starts_with("//") || line.starts_with("#[")) {
+            break;
+        }
+        start -= 1;
+    }
+    lines[start].0.start
+}
+
+/// Last line of the item starting on line `item`: the line itself for one-liners, otherwise
+/// the closing brace or the line ending in `;` indented like the item. Declarations without
+/// either, like multi-line trait methods, end before the next item that is not nested in them.
+fn item_end(lines: &[(Range<usize>, &str)], item: usize) -> usize {
+    let first = lines[item].1.trim_end();
+    if first.ends_with(';') || first.ends_with('}') {
+        return item;
+    }
+    let item_indent = indent(lines[item].1);
+    for (i, (_, line)) in lines.iter().enumerate().skip(item + 1) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || indent(line) > item_indent {
+            continue;
+        }
+        if indent(line) == item_indent && (trimmed.starts_with('}') || trimmed.ends_with(';')) {
+            return i;
+        }
+        if indent(line) < item_indent || item_signature(line).is_some() {
+            // End before the doc comments and attributes of the next item
+            let mut end = i - 1;
+            while end > item {
+                let previous = lines[end].1.trim();
+                if !(previous.is_empty()
+                    || previous.starts_with("//")
+                    || previous.starts_with("#["))
+                {
+                    break;
+                }
+                end -= 1;
+            }
+            return end;
+        }
+    }
+    lines.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::{Embedding, PrefixScheme};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+    /// Counts words and punctuation as tokens.
+    fn word_tokenizer() -> Tokenizer {
+        let model = WordLevel::builder()
+            .vocab(HashMap::from([("[UNK]".to_string(), 0)]))
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        tokenizer
+    }
+
+    /// Pretends to be an E5 model that accepts `max_input_tokens` tokens.
+    struct LimitedEmbedder {
+        max_input_tokens: usize,
+        tokenizer_file: PathBuf,
+    }
+
+    #[async_trait]
+    impl Embedder for LimitedEmbedder {
+        async fn embed_documents(&self, _texts: Vec<String>) -> Result<Vec<Embedding>> {
+            Err(anyhow!("LimitedEmbedder only chunks"))
+        }
+
+        async fn embed_query(&self, _query: &str) -> Result<Embedding> {
+            Err(anyhow!("LimitedEmbedder only chunks"))
+        }
+
+        fn dimension(&self) -> usize {
+            2
+        }
+
+        fn model_id(&self) -> &'static str {
+            "intfloat/multilingual-e5-small"
+        }
+
+        fn prefix_scheme(&self) -> PrefixScheme {
+            PrefixScheme::E5
+        }
+
+        fn max_input_tokens(&self) -> Option<usize> {
+            Some(self.max_input_tokens)
+        }
+
+        fn tokenizer_file(&self) -> Option<PathBuf> {
+            Some(self.tokenizer_file.clone())
+        }
+    }
+
+    fn section_texts(text: &str, strategy: ChunkStrategy) -> Vec<(String, Vec<String>)> {
+        sections(text, strategy)
+            .into_iter()
+            .map(|section| {
+                let blocks = section
+                    .blocks
+                    .iter()
+                    .map(|block| text[block.clone()].trim().to_string())
+                    .collect();
+                (section.heading_path.join(HEADING_SEPARATOR), blocks)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_split_markdown_on_headings() {
+        let text = "Intro\n\n# Ch. 8\n\n## Storing Keys with Hash Maps\n\n```rust\nlet mut \
+                    map = HashMap::new();\n\n# not a heading\n```\n\n- one\n- two\n  more\n\n\
+                    ## Updating\n\nText\n\n# Ch. 9\nErrors\n";
+        let sections = section_texts(text, ChunkStrategy::Markdown);
+        let paths: Vec<&str> = sections.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "",
+                "Ch. 8 > Storing Keys with Hash Maps",
+                "Ch. 8 > Updating",
+                "Ch. 9"
+            ]
+        );
+        assert_eq!(
+            sections[1].1,
+            vec![
+                "```rust\nlet mut map = HashMap::new();\n\n# not a heading\n```",
+                "- one\n- two\n  more"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_split_code_on_items() {
+        let text = "use std::fmt;\n\n/// A point.\n#[derive(Debug)]\npub struct Point {\n    \
+                    x: i32,\n}\n\nimpl Point {\n    /// Origin.\n    pub const fn origin() -> \
+                    Self {\n        Point { x: 0 }\n    }\n\n    fn x(&self) -> i32 {\n        \
+                    self.x\n    }\n}\n";
+        let sections = sections(text, ChunkStrategy::Code);
+        let paths: Vec<String> = sections
+            .iter()
+            .map(|section| section.heading_path.join(HEADING_SEPARATOR))
+            .collect();
+        assert_eq!(paths, vec!["", "pub struct Point", "impl Point"]);
+        assert!(text[sections[1].range.clone()].starts_with("/// A point."));
+        let blocks: Vec<&str> = sections[2]
+            .blocks
+            .iter()
+            .map(|block| text[block.clone()].trim())
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                "/// Origin.\n    pub const fn origin() -> Self {\n        Point { x: 0 }\n    }",
+                "fn x(&self) -> i32 {\n        self.x\n    }"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_end_declarations_without_body_at_the_next_item() {
+        let text = "pub trait Store {\n    fn get(\n        &self,\n        key: &str,\n    ) -> \
+                    Option<String>;\n\n    const NAMES: [&str; 2] = [\n        \"a\",\n        \
+                    \"b\",\n    ];\n\n    /// Number of keys.\n    fn len(&self)\n        -> \
+                    usize;\n}\n\nfn main() {}\n";
+        let sections = sections(text, ChunkStrategy::Code);
+        let paths: Vec<String> = sections
+            .iter()
+            .map(|section| section.heading_path.join(HEADING_SEPARATOR))
+            .collect();
+        assert_eq!(paths, vec!["pub trait Store", "fn main()"]);
+        let blocks: Vec<&str> = sections[0]
+            .blocks
+            .iter()
+            .map(|block| text[block.clone()].trim())
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                "fn get(\n        &self,\n        key: &str,\n    ) -> Option<String>;",
+                "const NAMES: [&str; 2] = [\n        \"a\",\n        \"b\",\n    ];",
+                "/// Number of keys.\n    fn len(&self)\n        -> usize;"
+            ]
+        );
+        for section in &sections {
+            for part in partition(&section.range, &section.blocks) {
+                assert!(section.range.start <= part.start && part.end <= section.range.end);
+            }
+        }
+    }
+
+    #[test]
+    fn should_overlap_chunks_within_size_range() {
+        let settings = ChunkingSettings {
+            tokenizer: "words".to_string(),
+            min_tokens: 4,
+            max_tokens: 8,
+            overlap_tokens: 2,
+            strategy: ChunkStrategy::Text,
+        };
+        let chunker = Chunker::new(word_tokenizer(), settings.clone());
+        let text = "Vectors store values.\n\nHash maps store keys and values.\n\nStrings are \
+                    collections of bytes that are valid UTF eight.\n";
+        let chunks = chunker.chunks(text, "book.txt");
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks[0].overlap, 0);
+        let mut stitched = String::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let chunk_text = &text[chunk.range.clone()];
+            assert!(chunk_text.split_whitespace().count() <= settings.max_tokens);
+            if i > 0 {
+                assert!(chunk.overlap > 0);
+                assert!(stitched.ends_with(&chunk_text[..chunk.overlap]));
+            }
+            stitched.push_str(&text[chunk.range.start + chunk.overlap..chunk.range.end]);
+        }
+        assert_eq!(
+            stitched.split_whitespace().collect::<Vec<_>>(),
+            text.split_whitespace().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_fit_chunks_into_model_limit() {
+        let dir = Path::new(".test_data/word_tokenizer");
+        fs::create_dir_all(dir).unwrap();
+        word_tokenizer()
+            .save(dir.join("tokenizer.json"), false)
+            .unwrap();
+        let embedder = LimitedEmbedder {
+            max_input_tokens: 42,
+            tokenizer_file: dir.join("tokenizer.json"),
+        };
+        let mut config = ChunkingConfig {
+            tokenizer: None,
+            min_tokens: None,
+            max_tokens: None,
+            overlap_tokens: None,
+            strategy: ChunkStrategy::Auto,
+            allow_truncation: false,
+        };
+        // `passage: ` takes 2 of the 42 tokens
+        let settings = init_chunker(&config, &embedder).unwrap().settings().clone();
+        assert_eq!(
+            settings,
+            ChunkingSettings {
+                tokenizer: "intfloat/multilingual-e5-small".to_string(),
+                min_tokens: 20,
+                max_tokens: 40,
+                overlap_tokens: 4,
+                strategy: ChunkStrategy::Auto,
+            }
+        );
+
+        config.max_tokens = Some(100);
+        assert!(init_chunker(&config, &embedder).is_err());
+        config.allow_truncation = true;
+        config.tokenizer = Some(dir.to_path_buf());
+        let settings = init_chunker(&config, &embedder).unwrap().settings().clone();
+        assert_eq!(settings.max_tokens, 100);
+        assert_eq!(settings.tokenizer, dir.display().to_string());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn should_pick_strategy_by_extension() {
+        let auto = ChunkStrategy::Auto;
+        assert_eq!(
+            auto.for_source("knowledge/ch08.md"),
+            ChunkStrategy::Markdown
+        );
+        assert_eq!(auto.for_source("src/lib.rs"), ChunkStrategy::Code);
+        assert_eq!(auto.for_source("book.txt"), ChunkStrategy::Text);
+        assert_eq!(auto.for_source("book.epub"), ChunkStrategy::Markdown);
+        assert_eq!(auto.for_source("book.pdf"), ChunkStrategy::Text);
+        assert_eq!(
+            ChunkStrategy::Text.for_source("ch08.md"),
+            ChunkStrategy::Text
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::record::Chunk;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+use tracing::warn;
+
+/// Instructions for the model on how to cite the sources in [`format_context`].
+pub const CITATION_INSTRUCTIONS: &str = "Every document in the CONTEXT starts with its number \
+in square brackets, e.g. `[1]`. End every sentence that uses a document with the numbers of \
+the documents it is based on, e.g. `[1]` or `[1][3]`. Never cite numbers that are not in the \
+CONTEXT.";
+
+/// A retrieved chunk with the number the model cites it by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    /// Number of the source in the prompt, starting at 1.
+    pub number: usize,
+    pub chunk: Chunk,
+}
+
+impl Source {
+    /// File and position of the chunk, e.g. `knowledge/ch08.md (Ch. 8 > Hash Maps, chars
+    /// 120-560)`. The character range is part of the label, so the same chunk always gets the
+    /// same label.
+    pub fn label(&self) -> String {
+        let meta = &self.chunk.metadata;
+        let mut location = Vec::new();
+        if let Some(page) = meta.page {
+            location.push(format!("p. {page}"));
+        }
+        if !meta.heading_path.is_empty() {
+            location.push(meta.heading_path.clone());
+        }
+        location.push(format!("chars {}-{}", meta.char_start, meta.char_end));
+        format!("{} ({})", meta.source, location.join(", "))
+    }
+}
+
+/// Number the chunks in the order they are given, starting at 1.
+pub fn number_sources(chunks: Vec<Chunk>) -> Vec<Source> {
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| Source {
+            number: i + 1,
+            chunk,
+        })
+        .collect()
+}
+
+/// Put the sources into the prompt, each delimited with `~~~` and preceded by its number and
+/// label. Tells the model explicitly if nothing relevant was found.
+pub fn format_context(sources: &[Source]) -> String {
+    if sources.is_empty() {
+        return "There is no CONTEXT, no document matches the question.".to_string();
+    }
+    sources
+        .iter()
+        .map(|source| {
+            format!(
+                "[{}] {}\n~~~\n{}\n~~~",
+                source.number,
+                source.label(),
+                source.chunk.text.trim()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// A `[n]` marker in an answer that refers to one of the sources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+    pub number: usize,
+    /// Byte range of the cited sentence in [`CitedAnswer::text`], without the markers.
+    pub sentence: Range<usize>,
+}
+
+/// A generated answer with its citations checked against the sources in the prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CitedAnswer {
+    /// The answer with markers of unknown sources removed.
+    pub text: String,
+    /// Citations in the order they appear in the answer.
+    pub citations: Vec<Citation>,
+    /// The cited sources, ordered by number.
+    pub sources: Vec<Source>,
+    /// Numbers the model cited that were not in the prompt.
+    pub unknown: Vec<usize>,
+}
+
+impl CitedAnswer {
+    /// Find the `[n]` and `[n, m]` markers in `answer`. Markers that don't refer to one of
+    /// `sources` are dropped from the text, brackets directly after a word are left alone.
+    pub fn resolve(answer: &str, sources: &[Source]) -> Self {
+        let mut text = String::with_capacity(answer.len());
+        let mut markers = Vec::new();
+        let mut unknown = BTreeSet::new();
+        let mut rest = answer;
+        while let Some(start) = rest.find('[') {
+            text.push_str(&rest[..start]);
+            // Brackets right after a word are indexing or types like `v[0]`, not markers
+            let after_word = answer[..answer.len() - rest.len() + start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '<' | '&' | '`'));
+            let Some((numbers, len)) = parse_marker(&rest[start..]).filter(|_| !after_word) else {
+                text.push('[');
+                rest = &rest[start + 1..];
+                continue;
+            };
+            let (known, missing): (Vec<usize>, Vec<usize>) = numbers
+                .into_iter()
+                .partition(|n| sources.iter().any(|source| source.number == *n));
+            unknown.extend(missing);
+            if known.is_empty() {
+                // Drop the space that separated the removed marker from the sentence
+                let trimmed = text.trim_end_matches(' ').len();
+                text.truncate(trimmed);
+            } else {
+                let list: Vec<String> = known.iter().map(ToString::to_string).collect();
+                markers.push((text.len(), known));
+                text.push('[');
+                text.push_str(&list.join(", "));
+                text.push(']');
+            }
+            rest = &rest[start + len..];
+        }
+        text.push_str(rest);
+        if !unknown.is_empty() {
+            warn!("The answer cites unknown sources {unknown:?}");
+        }
+
+        let citations: Vec<Citation> = markers
+            .into_iter()
+            .flat_map(|(position, numbers)| {
+                let sentence = sentence_before(&text, position);
+                numbers.into_iter().map(move |number| Citation {
+                    number,
+                    sentence: sentence.clone(),
+                })
+            })
+            .collect();
+        let cited: BTreeSet<usize> = citations.iter().map(|citation| citation.number).collect();
+        CitedAnswer {
+            text,
+            citations,
+            sources: sources
+                .iter()
+                .filter(|source| cited.contains(&source.number))
+                .cloned()
+                .collect(),
+            unknown: unknown.into_iter().collect(),
+        }
+    }
+
+    /// The cited sentence of `citation`.
+    pub fn sentence(&self, citation: &Citation) -> &str {
+        &self.text[citation.sentence.clone()]
+    }
+
+    /// Lists the cited sources, one per line after a `Sources:` line. Empty if nothing was
+    /// cited.
+    pub fn footer(&self) -> String {
+        if self.sources.is_empty() {
+            return String::new();
+        }
+        let lines: Vec<String> = self
+            .sources
+            .iter()
+            .map(|source| format!("[{}] {}", source.number, source.label()))
+            .collect();
+        format!("Sources:\n{}", lines.join("\n"))
+    }
+}
+
+impl fmt::Display for CitedAnswer {
+    /// The answer followed by the [`CitedAnswer::footer`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text.trim_end())?;
+        if !self.sources.is_empty() {
+            write!(f, "\n\n{}", self.footer())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a marker like `[1]` or `[1, 3]` at the start of `text`, returning the numbers and the
+/// length of the marker in bytes.
+fn parse_marker(text: &str) -> Option<(Vec<usize>, usize)> {
+    let end = text.find(']')?;
+    let numbers = text[1..end]
+        .split(',')
+        .map(|number| number.trim().parse::<usize>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some((numbers, end + 1))
+}
+
+/// Byte range of the sentence that ends at `position`, or right before the marker at
+/// `position` if the marker follows the full stop, like in `Vectors grow. [1]`.
+fn sentence_before(text: &str, position: usize) -> Range<usize> {
+    // Markers directly after another marker cite the same sentence
+    let mut head = text[..position].trim_end();
+    while head.ends_with(']') {
+        match head.rfind('[') {
+            Some(start) if parse_marker(&head[start..]).is_some() => {
+                head = head[..start].trim_end();
+            }
+            _ => break,
+        }
+    }
+    let end = head.len();
+    let body = head.trim_end_matches(['.', '!', '?']);
+    let start = body.rfind(['.', '!', '?', '\n']).map_or(0, |i| i + 1);
+    let sentence = &text[start..end];
+    let leading = sentence.len() - sentence.trim_start().len();
+    start + leading..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::ChunkMetadata;
+
+    fn sources() -> Vec<Source> {
+        let chunk = |id: i32, source: &str, heading_path: &str, page: Option<u32>| Chunk {
+            id,
+            text: format!("Text of chunk {id}"),
+            metadata: ChunkMetadata {
+                heading_path: heading_path.to_string(),
+                byte_start: 100,
+                byte_end: 200,
+                char_start: 90,
+                char_end: 180,
+                page,
+                ..ChunkMetadata::for_test(source, 2)
+            },
+        };
+        number_sources(vec![
+            chunk(4, "knowledge/ch08.md", "Ch. 8 > Hash Maps", None),
+            chunk(7, "book.pdf", "", Some(3)),
+        ])
+    }
+
+    #[test]
+    fn should_label_sources_with_file_and_position() {
+        let labels: Vec<String> = sources().iter().map(Source::label).collect();
+        assert_eq!(
+            labels,
+            vec![
+                "knowledge/ch08.md (Ch. 8 > Hash Maps, chars 90-180)",
+                "book.pdf (p. 3, chars 90-180)"
+            ]
+        );
+        assert!(format_context(&sources()).starts_with(
+            "[1] knowledge/ch08.md (Ch. 8 > Hash Maps, chars 90-180)\n~~~\nText of chunk 4\n~~~"
+        ));
+    }
+
+    #[test]
+    fn should_resolve_citations_to_sentences() {
+        let answer = CitedAnswer::resolve(
+            "Hash maps store pairs [1]. They grow [1,2][5]. Vectors are lists. [9]",
+            &sources(),
+        );
+        assert_eq!(
+            answer.text,
+            "Hash maps store pairs [1]. They grow [1, 2]. Vectors are lists."
+        );
+        let cited: Vec<(usize, &str)> = answer
+            .citations
+            .iter()
+            .map(|citation| (citation.number, answer.sentence(citation)))
+            .collect();
+        assert_eq!(
+            cited,
+            vec![
+                (1, "Hash maps store pairs"),
+                (1, "They grow"),
+                (2, "They grow")
+            ]
+        );
+        assert_eq!(answer.unknown, vec![5, 9]);
+        assert_eq!(
+            answer.to_string(),
+            "Hash maps store pairs [1]. They grow [1, 2]. Vectors are lists.\n\nSources:\n\
+             [1] knowledge/ch08.md (Ch. 8 > Hash Maps, chars 90-180)\n\
+             [2] book.pdf (p. 3, chars 90-180)"
+        );
+    }
+
+    #[test]
+    fn should_keep_brackets_that_are_no_markers() {
+        let answer = CitedAnswer::resolve("Use `v[0]`, `&[1, 2]` or `Vec<[u8]>`.", &sources());
+        assert_eq!(answer.text, "Use `v[0]`, `&[1, 2]` or `Vec<[u8]>`.");
+        assert!(answer.citations.is_empty());
+    }
+}
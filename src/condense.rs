@@ -0,0 +1,123 @@
+use crate::history::{transcript, Turn};
+use crate::llm::{ChatMessage, LlmBackend};
+use anyhow::Result;
+use tracing::{info, warn};
+
+/// Number of past turns shown to the model when rewriting a question.
+const RECENT_TURNS: usize = 3;
+/// Phrases of questions about the conversation itself, matched lowercase.
+const META_PHRASES: [&str; 8] = [
+    "my last question",
+    "my previous question",
+    "my first question",
+    "what did i ask",
+    "what did i say",
+    "what did you say",
+    "you just said",
+    "our conversation",
+];
+const CONDENSE_PROMPT: &str = "You turn the latest message of a user into a search query for \
+documentation. If the message is about the conversation itself, e.g. what the user asked \
+before or what the assistant said, reply with exactly CONVERSATION. Otherwise reply with \
+SEARCH: followed by a standalone query that can be understood without the conversation, \
+replacing pronouns and references like 'that' by what they refer to. Reply with nothing else.";
+
+/// What to do with a message of the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryPlan {
+    /// Retrieve documents with `query`, which can be understood without the conversation.
+    Retrieve { query: String },
+    /// The message is about the conversation itself, answer it from the history alone.
+    Conversation,
+}
+
+/// Decide whether `question` needs documents and rewrite follow-up questions like "and how
+/// does that work with lifetimes?" into standalone search queries, using the last turns of
+/// the conversation. The first question of a conversation is searched as is.
+pub async fn plan_query(llm: &dyn LlmBackend, turns: &[Turn], question: &str) -> Result<QueryPlan> {
+    let question = question.trim();
+    if is_meta_question(question) {
+        info!("Answering from the conversation without retrieval");
+        return Ok(QueryPlan::Conversation);
+    }
+    if turns.is_empty() {
+        return Ok(QueryPlan::Retrieve {
+            query: question.to_string(),
+        });
+    }
+
+    let transcript = transcript(&turns[turns.len().saturating_sub(RECENT_TURNS)..]);
+    let reply = llm
+        .chat(&[
+            ChatMessage::system(CONDENSE_PROMPT),
+            ChatMessage::user(format!(
+                "CONVERSATION:\n{transcript}\nLATEST MESSAGE: {question}"
+            )),
+        ])
+        .await?;
+    let plan = parse_plan(&reply, question);
+    match &plan {
+        QueryPlan::Retrieve { query } => info!("Searching for '{query}'"),
+        QueryPlan::Conversation => info!("Answering from the conversation without retrieval"),
+    }
+    Ok(plan)
+}
+
+/// Whether `question` obviously asks about the conversation, no model needed.
+pub fn is_meta_question(question: &str) -> bool {
+    let question = question.to_lowercase();
+    META_PHRASES.iter().any(|phrase| question.contains(phrase))
+}
+
+/// Read the model's reply to [`CONDENSE_PROMPT`]. Falls back to searching for `question` if
+/// the reply doesn't follow the format.
+fn parse_plan(reply: &str, question: &str) -> QueryPlan {
+    let reply = reply.trim();
+    if reply.to_uppercase().starts_with("CONVERSATION") {
+        return QueryPlan::Conversation;
+    }
+    let query = reply
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("SEARCH:"))
+        .map(|query| query.trim().trim_matches('"').trim())
+        .filter(|query| !query.is_empty());
+    if query.is_none() {
+        warn!("Unexpected reply to the query rewrite, searching for the question as is: {reply}");
+    }
+    QueryPlan::Retrieve {
+        query: query.unwrap_or(question).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_rewritten_queries() {
+        let question = "and how does that work with lifetimes?";
+        assert_eq!(
+            parse_plan("SEARCH: \"borrowing with lifetimes\"\n", question),
+            QueryPlan::Retrieve {
+                query: "borrowing with lifetimes".to_string()
+            }
+        );
+        assert_eq!(
+            parse_plan("Conversation.", question),
+            QueryPlan::Conversation
+        );
+        assert_eq!(
+            parse_plan("Sure! Here you go.", question),
+            QueryPlan::Retrieve {
+                query: question.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn should_recognize_meta_questions() {
+        assert!(is_meta_question("What was my last question?"));
+        assert!(is_meta_question("Summarize our conversation"));
+        assert!(!is_meta_question("What is a hash map?"));
+    }
+}
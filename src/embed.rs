@@ -1,27 +1,730 @@
+use crate::consts::{EMBEDDING_MODEL, OLLAMA_BASE_URL, OPENAI_BASE_URL};
+use anyhow::{anyhow, Context, Result};
+use async_openai::{config::OpenAIConfig, types::CreateEmbeddingRequestArgs, Client};
+use async_trait::async_trait;
+use fastembed::{
+    EmbeddingModel, InitOptions, InitOptionsUserDefined, TextEmbedding, TokenizerFiles,
+    UserDefinedEmbeddingModel,
+};
+use ollama_rs::Ollama;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
 
+pub type Embedding = Vec<f32>;
 
-use crate::consts::{EMBEDDING_MODEL, TOKENIZER_MODEL};
-use anyhow::{anyhow, Context, Result};
-use fastembed::{InitOptions, TextEmbedding};
-use text_splitter::TextSplitter;
-use tokenizers::Tokenizer;
-use tracing::{instrument, warn};
+const OLLAMA_MODEL: &str = "nomic-embed-text";
+const OPENAI_MODEL: &str = "text-embedding-3-small";
+/// Where fastembed keeps the downloaded models, in the layout of the `HuggingFace` cache.
+const FASTEMBED_CACHE_DIR: &str = ".fastembed_cache";
+/// Files besides the ONNX model that fastembed needs to set up the tokenizer.
+const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+/// Where the ONNX model may be located in a model directory, checked in order.
+const ONNX_FILES: [&str; 2] = ["model.onnx", "onnx/model.onnx"];
+/// Embedded once when a remote embedder is created to learn its dimension.
+const DIMENSION_PROBE: &str = "How many dimensions do you have?";
+
+/// Turns text into embeddings, independent of where the model runs. Implementations add the
+/// instruction prefixes of their [`PrefixScheme`], callers always pass plain text.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Embed chunks of documents that will be stored and searched.
+    async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Embedding>>;
+    /// Embed a search query.
+    async fn embed_query(&self, query: &str) -> Result<Embedding>;
+    /// Length of the embedding vectors.
+    fn dimension(&self) -> usize;
+    /// Identifies the model, e.g. `intfloat/multilingual-e5-small`.
+    fn model_id(&self) -> &str;
+    fn prefix_scheme(&self) -> PrefixScheme;
+    /// Longest input in tokens, prefix and special tokens included, that the model embeds
+    /// without cutting it off. `None` if unknown.
+    fn max_input_tokens(&self) -> Option<usize>;
+    /// The `tokenizer.json` of the model if it is stored locally.
+    fn tokenizer_file(&self) -> Option<PathBuf>;
+}
+
+/// Instruction prefixes some models were trained with to tell queries and documents apart.
+/// Leaving them out, or using them on only one side, degrades retrieval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PrefixScheme {
+    /// Embed text as is.
+    None,
+    /// `query: ` and `passage: `, used by the E5 family.
+    E5,
+    /// Instruction for queries only, used by BGE, mxbai-embed and arctic-embed.
+    Bge,
+    /// The Chinese BGE instruction for queries only.
+    BgeZh,
+    /// `search_query: ` and `search_document: `, used by nomic-embed.
+    Nomic,
+}
+
+impl PrefixScheme {
+    /// Guess the scheme from a model id like `BAAI/bge-small-en-v1.5`.
+    pub fn for_model(model_id: &str) -> Self {
+        let id = model_id.to_lowercase();
+        if id.contains("nomic-embed") {
+            PrefixScheme::Nomic
+        } else if id.contains("bge-") && id.contains("-zh") {
+            PrefixScheme::BgeZh
+        } else if ["bge-", "mxbai-embed", "arctic-embed"]
+            .iter()
+            .any(|name| id.contains(name))
+        {
+            PrefixScheme::Bge
+        } else if id.contains("e5-") {
+            PrefixScheme::E5
+        } else {
+            PrefixScheme::None
+        }
+    }
+
+    pub fn query_prefix(self) -> &'static str {
+        match self {
+            PrefixScheme::None => "",
+            PrefixScheme::E5 => "query: ",
+            PrefixScheme::Bge => "Represent this sentence for searching relevant passages: ",
+            PrefixScheme::BgeZh => "为这个句子生成表示以用于检索相关文章：",
+            PrefixScheme::Nomic => "search_query: ",
+        }
+    }
+
+    pub fn document_prefix(self) -> &'static str {
+        match self {
+            PrefixScheme::None | PrefixScheme::Bge | PrefixScheme::BgeZh => "",
+            PrefixScheme::E5 => "passage: ",
+            PrefixScheme::Nomic => "search_document: ",
+        }
+    }
+
+    /// Name used on the command line and in the table metadata.
+    pub fn name(self) -> &'static str {
+        match self {
+            PrefixScheme::None => "none",
+            PrefixScheme::E5 => "e5",
+            PrefixScheme::Bge => "bge",
+            PrefixScheme::BgeZh => "bge-zh",
+            PrefixScheme::Nomic => "nomic",
+        }
+    }
+
+    pub fn query(self, query: &str) -> String {
+        format!("{}{query}", self.query_prefix())
+    }
+
+    pub fn documents(self, texts: Vec<String>) -> Vec<String> {
+        let prefix = self.document_prefix();
+        if prefix.is_empty() {
+            return texts;
+        }
+        texts
+            .into_iter()
+            .map(|text| format!("{prefix}{text}"))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmbeddingProvider {
+    /// Local ONNX models via fastembed.
+    Fastembed,
+    /// Ollama's `/api/embeddings` endpoint.
+    Ollama,
+    /// Any server implementing the `OpenAI` `/v1/embeddings` endpoint, e.g. `llama.cpp` or
+    /// llamafile.
+    Openai,
+}
+
+/// Selects and configures the embedding model.
+#[derive(Debug, Clone, clap::Args)]
+pub struct EmbedderConfig {
+    #[arg(
+        long = "embedding-provider",
+        env = "EMBEDDING_PROVIDER",
+        value_enum,
+        default_value_t = EmbeddingProvider::Fastembed
+    )]
+    pub provider: EmbeddingProvider,
+    /// Model name, e.g. `intfloat/multilingual-e5-small` for fastembed or `nomic-embed-text`
+    /// for Ollama. Every provider has a default.
+    #[arg(long = "embedding-model", env = "EMBEDDING_MODEL")]
+    pub model: Option<String>,
+    /// Base URL of the Ollama or OpenAI-compatible server.
+    #[arg(long = "embedding-base-url", env = "EMBEDDING_BASE_URL")]
+    pub base_url: Option<String>,
+    #[arg(
+        long = "embedding-api-key",
+        env = "EMBEDDING_API_KEY",
+        default_value = "sk-no-key-required",
+        hide_env_values = true
+    )]
+    pub api_key: String,
+    /// Instruction prefixes for queries and documents, guessed from the model name if not set.
+    #[arg(long = "embedding-prefix", env = "EMBEDDING_PREFIX", value_enum)]
+    pub prefix: Option<PrefixScheme>,
+    /// Directory with an ONNX model (`model.onnx` or `onnx/model.onnx`) and its tokenizer
+    /// files, used with fastembed instead of a downloaded model. `--embedding-model` names it.
+    #[arg(long = "embedding-model-dir", env = "EMBEDDING_MODEL_DIR")]
+    pub model_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub cache: ModelCache,
+}
+
+/// Where fastembed models are downloaded to and whether downloading is allowed at all.
+#[derive(Debug, Clone, clap::Args)]
+pub struct ModelCache {
+    /// Directory of downloaded models, in the layout of the `HuggingFace` cache.
+    #[arg(long = "model-cache", env = "MODEL_CACHE", default_value = FASTEMBED_CACHE_DIR)]
+    pub dir: PathBuf,
+    /// Never download models, only load them from `--model-cache` or
+    /// `--embedding-model-dir`.
+    #[arg(long, env = "OFFLINE")]
+    pub offline: bool,
+}
+
+impl ModelCache {
+    /// Path of a cached file of the model `model_code`, e.g. `BAAI/bge-small-en-v1.5`.
+    pub fn file(&self, model_code: &str, name: &str) -> Option<PathBuf> {
+        hf_hub::Cache::new(self.dir.clone())
+            .model(model_code.to_string())
+            .get(name)
+    }
+
+    /// Read the ONNX model and the tokenizer files of `model_code` from the cache, without
+    /// downloading anything.
+    pub fn read_model(&self, model_code: &str, onnx_file: &str) -> Result<LocalModel> {
+        LocalModel::read(
+            &format!("the cache {} of {model_code}", self.dir.display()),
+            &[onnx_file],
+            |name| self.file(model_code, name),
+        )
+    }
+}
+
+/// An ONNX model and its tokenizer files, read from disk.
+pub struct LocalModel {
+    pub onnx_file: Vec<u8>,
+    pub tokenizer_files: TokenizerFiles,
+    /// Where `tokenizer.json` was read from.
+    pub tokenizer_path: PathBuf,
+}
+
+impl LocalModel {
+    /// Read a model from a directory laid out like a `HuggingFace` model repository.
+    pub fn read_dir(dir: &Path) -> Result<Self> {
+        anyhow::ensure!(
+            dir.is_dir(),
+            "Model directory {} does not exist",
+            dir.display()
+        );
+        LocalModel::read(&dir.display().to_string(), &ONNX_FILES, |name| {
+            Some(dir.join(name)).filter(|path| path.is_file())
+        })
+    }
+
+    /// `find` locates a file by its name. The first of `onnx_files` that exists is the model.
+    /// Fails naming every missing file of the model at `location`.
+    fn read(
+        location: &str,
+        onnx_files: &[&str],
+        find: impl Fn(&str) -> Option<PathBuf>,
+    ) -> Result<Self> {
+        let onnx = onnx_files.iter().find_map(|name| find(name));
+        let tokenizer: Vec<_> = TOKENIZER_FILES.iter().map(|name| find(name)).collect();
+        let mut missing = Vec::new();
+        if onnx.is_none() {
+            missing.push(onnx_files.join(" or "));
+        }
+        for (name, path) in TOKENIZER_FILES.iter().zip(&tokenizer) {
+            if path.is_none() {
+                missing.push((*name).to_string());
+            }
+        }
+        anyhow::ensure!(
+            missing.is_empty(),
+            "Missing model files in {location}: {}",
+            missing.join(", ")
+        );
+        let read = |path: &Option<PathBuf>| {
+            let path = path.as_ref().expect("Missing files were reported");
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+        };
+        Ok(LocalModel {
+            onnx_file: read(&onnx)?,
+            tokenizer_files: TokenizerFiles {
+                tokenizer_file: read(&tokenizer[0])?,
+                config_file: read(&tokenizer[1])?,
+                special_tokens_map_file: read(&tokenizer[2])?,
+                tokenizer_config_file: read(&tokenizer[3])?,
+            },
+            tokenizer_path: tokenizer[0].clone().expect("Missing files were reported"),
+        })
+    }
+}
 
+/// Create the embedder selected by `config`.
 #[instrument]
-pub fn init_splitter() -> Result<TextSplitter<Tokenizer>> {
-    let tokenizer =
-        Tokenizer::from_pretrained(TOKENIZER_MODEL, None).map_err(|e| anyhow!("{e:#?}"))?;
-    let splitter = TextSplitter::new(tokenizer).with_trim_chunks(true);
-    Ok(splitter)
+pub async fn init_embedder(config: &EmbedderConfig) -> Result<Box<dyn Embedder>> {
+    let embedder: Box<dyn Embedder> = match config.provider {
+        EmbeddingProvider::Fastembed => {
+            if let Some(dir) = &config.model_dir {
+                Box::new(FastEmbedder::from_dir(
+                    dir,
+                    config.model.as_deref(),
+                    config.prefix,
+                )?)
+            } else {
+                let model = match &config.model {
+                    Some(name) => parse_fastembed_model(name)?,
+                    None => EMBEDDING_MODEL,
+                };
+                Box::new(FastEmbedder::new(model, config.prefix, &config.cache)?)
+            }
+        }
+        EmbeddingProvider::Ollama => Box::new(
+            OllamaEmbedder::new(
+                config.base_url.as_deref().unwrap_or(OLLAMA_BASE_URL),
+                config.model.as_deref().unwrap_or(OLLAMA_MODEL),
+                config.prefix,
+            )
+            .await?,
+        ),
+        EmbeddingProvider::Openai => Box::new(
+            OpenAiEmbedder::new(
+                config.base_url.as_deref().unwrap_or(OPENAI_BASE_URL),
+                config.model.as_deref().unwrap_or(OPENAI_MODEL),
+                &config.api_key,
+                config.prefix,
+            )
+            .await?,
+        ),
+    };
+    info!(
+        "Using embedding model {} with {} dimensions and {:?} prefixes",
+        embedder.model_id(),
+        embedder.dimension(),
+        embedder.prefix_scheme()
+    );
+    Ok(embedder)
 }
 
+/// Accepts the `HuggingFace` model code (`intfloat/multilingual-e5-small`) or the fastembed enum
+/// name (`MultilingualE5Small`), ignoring case.
+fn parse_fastembed_model(name: &str) -> Result<EmbeddingModel> {
+    let supported = TextEmbedding::list_supported_models();
+    supported
+        .iter()
+        .find(|info| {
+            info.model_code.eq_ignore_ascii_case(name)
+                || format!("{:?}", info.model).eq_ignore_ascii_case(name)
+        })
+        .map(|info| info.model.clone())
+        .ok_or_else(|| {
+            let codes: Vec<_> = supported
+                .iter()
+                .map(|info| info.model_code.as_str())
+                .collect();
+            anyhow!("Unknown fastembed model '{name}', supported are: {codes:?}")
+        })
+}
+
+/// Load `model` from the cache, downloading it first unless the cache is offline.
 #[instrument]
-pub fn init_model() -> Result<TextEmbedding> {
+pub fn init_model(model: EmbeddingModel, cache: &ModelCache) -> Result<TextEmbedding> {
+    if cache.offline {
+        let info = TextEmbedding::get_model_info(&model);
+        let files = cache.read_model(&info.model_code, &info.model_file)?;
+        return init_local_model(files).with_context(|| format!("Failed to initialize {model}"));
+    }
     let model: TextEmbedding = TextEmbedding::try_new(InitOptions {
-        model_name: EMBEDDING_MODEL,
+        model_name: model.clone(),
+        cache_dir: cache.dir.clone(),
         show_download_progress: true,
         ..Default::default()
     })
-    .context("Failed to intitialize model {EMBEDDING_MODEL:#?}")?;
+    .with_context(|| format!("Failed to intitialize model {model:#?}"))?;
     Ok(model)
 }
+
+fn init_local_model(files: LocalModel) -> Result<TextEmbedding> {
+    TextEmbedding::try_new_from_user_defined(
+        UserDefinedEmbeddingModel {
+            onnx_file: files.onnx_file,
+            tokenizer_files: files.tokenizer_files,
+        },
+        InitOptionsUserDefined::default(),
+    )
+}
+
+pub fn get_embedding_size(model: &EmbeddingModel) -> Option<usize> {
+    TextEmbedding::list_supported_models()
+        .iter()
+        .find(|info| info.model == *model)
+        .map(|info| info.dim)
+}
+
+/// Scale `embedding` to unit length. Zero vectors have no direction and are left as they are.
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in embedding.iter_mut() {
+            *x /= norm;
+        }
+    }
+}
+
+/// Local ONNX model run by fastembed.
+pub struct FastEmbedder {
+    model: Arc<TextEmbedding>,
+    model_id: String,
+    dimension: usize,
+    prefix: PrefixScheme,
+    max_input_tokens: Option<usize>,
+    tokenizer_file: Option<PathBuf>,
+}
+
+impl FastEmbedder {
+    /// Load one of the models fastembed supports from `cache`.
+    pub fn new(
+        model: EmbeddingModel,
+        prefix: Option<PrefixScheme>,
+        cache: &ModelCache,
+    ) -> Result<Self> {
+        let model_id = TextEmbedding::get_model_info(&model).model_code;
+        let dimension = get_embedding_size(&model)
+            .ok_or_else(|| anyhow!("Unknown dimension of embedding model {model_id}"))?;
+        let model = init_model(model, cache)?;
+        // Only there once init_model has downloaded the model
+        let tokenizer_file = cache.file(&model_id, "tokenizer.json");
+        Ok(FastEmbedder::with_model(
+            model,
+            model_id,
+            dimension,
+            prefix,
+            tokenizer_file,
+        ))
+    }
+
+    /// Load the model in `dir`, named after the directory unless `model_id` is given. The
+    /// dimension is probed, as the model may be unknown to fastembed.
+    pub fn from_dir(
+        dir: &Path,
+        model_id: Option<&str>,
+        prefix: Option<PrefixScheme>,
+    ) -> Result<Self> {
+        let model_id = match model_id {
+            Some(model_id) => model_id.to_string(),
+            None => dir
+                .file_name()
+                .unwrap_or(dir.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let files = LocalModel::read_dir(dir)?;
+        let tokenizer_file = Some(files.tokenizer_path.clone());
+        let model = init_local_model(files)
+            .with_context(|| format!("Failed to initialize the model in {}", dir.display()))?;
+        let dimension = model
+            .embed(vec![DIMENSION_PROBE], None)?
+            .first()
+            .map_or(0, Vec::len);
+        Ok(FastEmbedder::with_model(
+            model,
+            model_id,
+            dimension,
+            prefix,
+            tokenizer_file,
+        ))
+    }
+
+    fn with_model(
+        model: TextEmbedding,
+        model_id: String,
+        dimension: usize,
+        prefix: Option<PrefixScheme>,
+        tokenizer_file: Option<PathBuf>,
+    ) -> Self {
+        // fastembed truncates inputs to the shorter of its own and the model's limit
+        let max_input_tokens = model
+            .tokenizer
+            .get_truncation()
+            .map(|truncation| truncation.max_length);
+        FastEmbedder {
+            model: Arc::new(model),
+            prefix: prefix.unwrap_or_else(|| PrefixScheme::for_model(&model_id)),
+            model_id,
+            dimension,
+            max_input_tokens,
+            tokenizer_file,
+        }
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        let model = self.model.clone();
+        // Inference is CPU bound, keep it off the async workers
+        tokio::task::spawn_blocking(move || model.embed(texts, None)).await?
+    }
+}
+
+#[async_trait]
+impl Embedder for FastEmbedder {
+    async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        self.embed(self.prefix.documents(texts)).await
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Embedding> {
+        self.embed(vec![self.prefix.query(query)])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("Model returned no embedding"))
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn prefix_scheme(&self) -> PrefixScheme {
+        self.prefix
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        self.max_input_tokens
+    }
+
+    fn tokenizer_file(&self) -> Option<PathBuf> {
+        self.tokenizer_file.clone()
+    }
+}
+
+/// Embeddings from a model served by Ollama.
+pub struct OllamaEmbedder {
+    ollama: Ollama,
+    model: String,
+    dimension: usize,
+    prefix: PrefixScheme,
+}
+
+impl OllamaEmbedder {
+    /// Connects to `base_url`, e.g. `http://127.0.0.1:11434`, and probes the dimension.
+    pub async fn new(base_url: &str, model: &str, prefix: Option<PrefixScheme>) -> Result<Self> {
+        let (host, port) = split_host_port(base_url)?;
+        let mut embedder = OllamaEmbedder {
+            ollama: Ollama::new(host, port),
+            model: model.to_string(),
+            dimension: 0,
+            prefix: prefix.unwrap_or_else(|| PrefixScheme::for_model(model)),
+        };
+        embedder.dimension = embedder.embed(DIMENSION_PROBE.to_string()).await?.len();
+        Ok(embedder)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn embed(&self, text: String) -> Result<Embedding> {
+        let response = self
+            .ollama
+            .generate_embeddings(self.model.clone(), text, None)
+            .await
+            .map_err(|e| anyhow!("Ollama failed to embed with {}: {e}", self.model))?;
+        Ok(response.embeddings.into_iter().map(|x| x as f32).collect())
+    }
+}
+
+/// Split `http://host:port` into the parts `Ollama::new` expects.
+pub(crate) fn split_host_port(base_url: &str) -> Result<(String, u16)> {
+    let base_url = base_url.trim_end_matches('/');
+    match base_url.rsplit_once(':') {
+        Some((host, port)) if !port.starts_with('/') => {
+            let port = port
+                .parse()
+                .with_context(|| format!("Invalid port in {base_url}"))?;
+            Ok((host.to_string(), port))
+        }
+        _ => {
+            warn!("No port in {base_url}, assuming Ollama's default 11434");
+            Ok((base_url.to_string(), 11434))
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        // The endpoint takes one prompt per request
+        for text in self.prefix.documents(texts) {
+            embeddings.push(self.embed(text).await?);
+        }
+        Ok(embeddings)
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Embedding> {
+        self.embed(self.prefix.query(query)).await
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn prefix_scheme(&self) -> PrefixScheme {
+        self.prefix
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    fn tokenizer_file(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Embeddings from an OpenAI-compatible `/v1/embeddings` endpoint.
+pub struct OpenAiEmbedder {
+    client: Client<OpenAIConfig>,
+    model: String,
+    dimension: usize,
+    prefix: PrefixScheme,
+}
+
+impl OpenAiEmbedder {
+    /// Connects to `base_url`, e.g. `http://localhost:8080/v1`, and probes the dimension.
+    pub async fn new(
+        base_url: &str,
+        model: &str,
+        api_key: &str,
+        prefix: Option<PrefixScheme>,
+    ) -> Result<Self> {
+        let config = OpenAIConfig::new()
+            .with_api_key(api_key)
+            .with_api_base(base_url);
+        let mut embedder = OpenAiEmbedder {
+            client: Client::with_config(config),
+            model: model.to_string(),
+            dimension: 0,
+            prefix: prefix.unwrap_or_else(|| PrefixScheme::for_model(model)),
+        };
+        let probe = embedder.embed(vec![DIMENSION_PROBE.to_string()]).await?;
+        embedder.dimension = probe.first().map_or(0, Vec::len);
+        Ok(embedder)
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(&self.model)
+            .input(texts)
+            .build()?;
+        let mut response = self
+            .client
+            .embeddings()
+            .create(request)
+            .await
+            .with_context(|| format!("Failed to embed with {}", self.model))?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response.data.into_iter().map(|e| e.embedding).collect())
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+        self.embed(self.prefix.documents(texts)).await
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Embedding> {
+        self.embed(vec![self.prefix.query(query)])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("{} returned no embedding", self.model))
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn prefix_scheme(&self) -> PrefixScheme {
+        self.prefix
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    fn tokenizer_file(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_pick_prefix_scheme_from_model_id() {
+        let cases = [
+            ("intfloat/multilingual-e5-small", PrefixScheme::E5),
+            ("BAAI/bge-small-en-v1.5", PrefixScheme::Bge),
+            ("Xenova/bge-small-zh-v1.5", PrefixScheme::BgeZh),
+            ("mxbai-embed-large", PrefixScheme::Bge),
+            ("nomic-embed-text", PrefixScheme::Nomic),
+            ("sentence-transformers/all-MiniLM-L6-v2", PrefixScheme::None),
+        ];
+        for (model_id, scheme) in cases {
+            assert_eq!(PrefixScheme::for_model(model_id), scheme, "{model_id}");
+        }
+        assert_eq!(PrefixScheme::E5.query("rust"), "query: rust");
+        assert_eq!(
+            PrefixScheme::Bge.documents(vec!["rust".to_string()]),
+            vec!["rust"]
+        );
+    }
+
+    #[test]
+    fn should_name_missing_model_files() {
+        let dir = Path::new(".test_data/e5-small");
+        fs::create_dir_all(dir.join("onnx")).unwrap();
+        fs::write(dir.join("onnx/model.onnx"), b"onnx").unwrap();
+        fs::write(dir.join("tokenizer.json"), b"{}").unwrap();
+        let error = LocalModel::read_dir(dir).err().unwrap().to_string();
+        assert!(error.ends_with("config.json, special_tokens_map.json, tokenizer_config.json"));
+
+        for name in &TOKENIZER_FILES[1..] {
+            fs::write(dir.join(name), b"{}").unwrap();
+        }
+        let model = LocalModel::read_dir(dir).unwrap();
+        assert_eq!(model.onnx_file, b"onnx");
+        assert_eq!(model.tokenizer_path, dir.join("tokenizer.json"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn should_normalize_to_unit_length() {
+        let mut embedding = vec![3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, vec![0.6, 0.8]);
+        let mut zero = vec![0.0, 0.0];
+        normalize(&mut zero);
+        assert_eq!(zero, vec![0.0, 0.0]);
+    }
+}
@@ -1,65 +1,1063 @@
-use anyhow::{anyhow, Context, Result};
-use arrow_array::{RecordBatch, RecordBatchIterator};
-use arrow_schema::{DataType, Field, Schema};
-use lancedb::{connection::Connection, Table};
+use crate::chunk::ChunkStrategy;
+use crate::embed::{normalize, Embedder, Embedding, PrefixScheme};
+use crate::fts::FullTextIndex;
+use crate::ingest::ChunkingSettings;
+use crate::rank::reciprocal_rank_fusion;
+use crate::record::{
+    check_metadata_key, chunks_from_batch, column, embeddings_from_batch, embeddings_schema,
+    extra_columns, quote_literal, to_record_batch, Chunk, ChunkMetadata,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_array::{Float32Array, Int32Array, RecordBatch, RecordBatchIterator, StringArray};
+use arrow_schema::Schema;
+use clap::ValueEnum;
+use futures::TryStreamExt;
+use lancedb::connection::CreateTableMode;
+use lancedb::index::vector::IvfPqIndexBuilder;
+use lancedb::index::Index;
+use lancedb::query::{ExecutableQuery, QueryBase, Select, VectorQuery};
+use lancedb::table::NewColumnTransform;
+use lancedb::{Connection, DistanceType, Table};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tracing::{debug, info, instrument};
 
 const TABLE_NAME: &str = "EmbeddingsTable";
-const EMBEDDINGSIZE: i32 = 1024;
 const URI: &str = ".data/embeddingsdb";
 
-pub struct Client {
-    pub uri: String,
-    pub conn: Connection,
-}
+const DEFAULT_TOP_K: usize = 2;
+/// `LanceDB` default, searches this many of the IVF partitions closest to the query.
+const DEFAULT_NPROBES: usize = 20;
+/// Product quantization trains 256 centroids per sub-vector and needs at least as many rows.
+const MIN_INDEX_ROWS: usize = 256;
+/// Factor by which filtered keyword searches fetch more hits until enough of them match.
+const KEYWORD_OVERFETCH: usize = 4;
+
+const MODEL_KEY: &str = "rag.embedding_model";
+const DIMENSION_KEY: &str = "rag.embedding_dimension";
+const PREFIX_KEY: &str = "rag.prefix_scheme";
+const TOKENIZER_KEY: &str = "rag.tokenizer";
+const MIN_TOKENS_KEY: &str = "rag.min_tokens";
+const MAX_TOKENS_KEY: &str = "rag.max_tokens";
+const OVERLAP_TOKENS_KEY: &str = "rag.overlap_tokens";
+const STRATEGY_KEY: &str = "rag.chunk_strategy";
+const METRIC_KEY: &str = "rag.metric";
 
+/// Where the embeddings table lives.
+#[derive(Debug, Clone)]
 pub struct Config {
     pub uri: String,
     pub table_name: String,
-    pub schema: Arc<Schema>,
 }
 
 impl Config {
-    pub fn new(uri: String, table_name: String, schema: Arc<Schema>) -> Self {
-        Config {
+    pub fn new(uri: String, table_name: String) -> Self {
+        Config { uri, table_name }
+    }
+
+    /// Read the database location from the `DATABASE_PATH` environment variable.
+    pub fn from_env() -> Result<Self> {
+        let uri = env::var("DATABASE_PATH").context("Environment var DATABASE_PATH must be set")?;
+        Ok(Config {
             uri,
-            table_name,
-            schema,
-        }
+            ..Default::default()
+        })
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("id", DataType::Int32, false),
-            Field::new("text", DataType::Utf8, true),
-            Field::new(
-                "embedding",
-                DataType::FixedSizeList(
-                    Arc::new(Field::new("item", DataType::Float32, true)),
-                    EMBEDDINGSIZE,
-                ),
-                true,
-            ),
-        ]));
         Config {
             uri: URI.to_string(),
             table_name: TABLE_NAME.to_string(),
+        }
+    }
+}
+
+/// Distance between embeddings, used to build the vector index and by every search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Metric {
+    /// Squared euclidean distance.
+    L2,
+    /// One minus the cosine similarity.
+    #[default]
+    Cosine,
+    /// Negative dot product.
+    Dot,
+}
+
+impl Metric {
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::L2 => "l2",
+            Metric::Cosine => "cosine",
+            Metric::Dot => "dot",
+        }
+    }
+
+    fn distance_type(self) -> DistanceType {
+        match self {
+            Metric::L2 => DistanceType::L2,
+            Metric::Cosine => DistanceType::Cosine,
+            Metric::Dot => DistanceType::Dot,
+        }
+    }
+
+    /// L2 and dot only rank like cosine similarity for unit length embeddings, which not every
+    /// embedder produces, so the store normalizes embeddings for them.
+    pub fn needs_unit_length(self) -> bool {
+        self != Metric::Cosine
+    }
+
+    /// Cosine similarity corresponding to a `distance` reported by `LanceDB`. Relies on unit
+    /// length embeddings for L2 and dot.
+    pub fn similarity(self, distance: f32) -> f32 {
+        match self {
+            Metric::L2 => 1.0 - distance / 2.0,
+            Metric::Cosine => 1.0 - distance,
+            Metric::Dot => -distance,
+        }
+    }
+}
+
+/// How a table was built, persisted in its schema metadata. Querying a table with another
+/// embedding model, or extending it with other chunking settings, silently gives bad results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSettings {
+    pub model_id: String,
+    pub dimension: usize,
+    pub prefix_scheme: PrefixScheme,
+    pub chunking: ChunkingSettings,
+    pub metric: Metric,
+}
+
+impl TableSettings {
+    pub fn new(embedder: &dyn Embedder, chunking: ChunkingSettings, metric: Metric) -> Self {
+        TableSettings {
+            model_id: embedder.model_id().to_string(),
+            dimension: embedder.dimension(),
+            prefix_scheme: embedder.prefix_scheme(),
+            chunking,
+            metric,
+        }
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (MODEL_KEY.to_string(), self.model_id.clone()),
+            (DIMENSION_KEY.to_string(), self.dimension.to_string()),
+            (
+                PREFIX_KEY.to_string(),
+                self.prefix_scheme.name().to_string(),
+            ),
+            (TOKENIZER_KEY.to_string(), self.chunking.tokenizer.clone()),
+            (
+                MIN_TOKENS_KEY.to_string(),
+                self.chunking.min_tokens.to_string(),
+            ),
+            (
+                MAX_TOKENS_KEY.to_string(),
+                self.chunking.max_tokens.to_string(),
+            ),
+            (
+                OVERLAP_TOKENS_KEY.to_string(),
+                self.chunking.overlap_tokens.to_string(),
+            ),
+            (
+                STRATEGY_KEY.to_string(),
+                self.chunking.strategy.name().to_string(),
+            ),
+            (METRIC_KEY.to_string(), self.metric.name().to_string()),
+        ])
+    }
+
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+        let get = |key: &str| {
+            metadata.get(key).map(String::as_str).ok_or_else(|| {
+                anyhow!("Table metadata lacks '{key}', please re-ingest your documents")
+            })
+        };
+        let tokens = |key: &str| {
+            get(key)?
+                .parse::<usize>()
+                .with_context(|| format!("Invalid value for '{key}'"))
+        };
+        Ok(TableSettings {
+            model_id: get(MODEL_KEY)?.to_string(),
+            dimension: get(DIMENSION_KEY)?
+                .parse()
+                .context("Invalid embedding dimension")?,
+            prefix_scheme: PrefixScheme::from_str(get(PREFIX_KEY)?, false)
+                .map_err(|e| anyhow!("Invalid prefix scheme: {e}"))?,
+            chunking: ChunkingSettings {
+                tokenizer: get(TOKENIZER_KEY)?.to_string(),
+                min_tokens: tokens(MIN_TOKENS_KEY)?,
+                max_tokens: tokens(MAX_TOKENS_KEY)?,
+                overlap_tokens: tokens(OVERLAP_TOKENS_KEY)?,
+                strategy: ChunkStrategy::from_str(get(STRATEGY_KEY)?, false)
+                    .map_err(|e| anyhow!("Invalid chunk strategy: {e}"))?,
+            },
+            metric: Metric::from_str(get(METRIC_KEY)?, false)
+                .map_err(|e| anyhow!("Invalid metric: {e}"))?,
+        })
+    }
+
+    /// Fail unless `embedder` produces embeddings comparable to the stored ones.
+    pub fn check_embedder(&self, embedder: &dyn Embedder) -> Result<()> {
+        let expected = (self.model_id.as_str(), self.dimension, self.prefix_scheme);
+        let actual = (
+            embedder.model_id(),
+            embedder.dimension(),
+            embedder.prefix_scheme(),
+        );
+        if expected != actual {
+            bail!(
+                "The table was built with embedding model {} ({} dimensions, {} prefixes), but \
+                 the configured model is {} ({} dimensions, {} prefixes). Configure the same \
+                 embedding model or re-ingest your documents.",
+                self.model_id,
+                self.dimension,
+                self.prefix_scheme.name(),
+                embedder.model_id(),
+                embedder.dimension(),
+                embedder.prefix_scheme().name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A chunk returned by a similarity search.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub chunk: Chunk,
+    /// Distance between query and chunk embedding as reported by `LanceDB`, lower is closer.
+    pub distance: f32,
+    /// BM25 score if the chunk was found by the keyword search.
+    pub keyword_score: Option<f32>,
+    /// Score the hits are ordered by, higher is better. The similarity for vector search, the
+    /// fused reciprocal rank for hybrid search.
+    pub score: f32,
+    /// Cosine similarity between query and chunk derived from the distance, see
+    /// [`Metric::similarity`].
+    pub similarity: f32,
+    /// Relevance assigned by a reranker, if the hits were reranked.
+    pub rerank_score: Option<f32>,
+    /// Stored embedding of the chunk.
+    pub embedding: Embedding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchMode {
+    /// Nearest neighbors of the query embedding.
+    Vector,
+    /// Vector and BM25 keyword search fused with reciprocal rank fusion. Finds exact terms
+    /// like `Box<dyn Trait>` or error codes that embeddings tend to miss.
+    Hybrid,
+}
+
+/// Controls how many and which chunks a search returns.
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+pub struct SearchParams {
+    /// Number of chunks to retrieve.
+    #[arg(short = 'k', long = "top-k", env = "TOP_K", default_value_t = DEFAULT_TOP_K)]
+    pub k: usize,
+    /// Drop chunks whose cosine similarity to the query is below this value, between -1 and 1.
+    /// In hybrid mode chunks found by the keyword search are kept regardless.
+    #[arg(long, env = "MIN_SIMILARITY")]
+    pub min_similarity: Option<f32>,
+    #[arg(long, env = "SEARCH_MODE", value_enum, default_value_t = SearchMode::Vector)]
+    pub mode: SearchMode,
+    /// Weight of the vector ranking in hybrid mode.
+    #[arg(long, env = "VECTOR_WEIGHT", default_value_t = 1.0)]
+    pub vector_weight: f32,
+    /// Weight of the keyword ranking in hybrid mode.
+    #[arg(long, env = "KEYWORD_WEIGHT", default_value_t = 1.0)]
+    pub keyword_weight: f32,
+    /// Number of IVF partitions searched once the table has a vector index. Higher values
+    /// find more of the true nearest neighbors but are slower.
+    #[arg(long, env = "NPROBES", default_value_t = DEFAULT_NPROBES)]
+    pub nprobes: usize,
+    /// Fetch this many times `k` candidates from the vector index and re-rank them by their
+    /// exact distance, which makes up for the lossy compression of the index.
+    #[arg(long, env = "REFINE_FACTOR")]
+    pub refine_factor: Option<u32>,
+    /// SQL predicate on the table columns, e.g. `source = 'knowledge/book.txt'`.
+    #[arg(skip)]
+    pub filter: Option<String>,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            k: DEFAULT_TOP_K,
+            min_similarity: None,
+            mode: SearchMode::Vector,
+            vector_weight: 1.0,
+            keyword_weight: 1.0,
+            nprobes: DEFAULT_NPROBES,
+            refine_factor: None,
+            filter: None,
+        }
+    }
+}
+
+/// When to build the `IVF_PQ` index on the `embedding` column. Until then, and for rows added
+/// since, searches compare the query with every embedding, which is exact but gets slow.
+#[derive(Debug, Clone, clap::Args)]
+pub struct IndexParams {
+    /// Build the vector index once the table has this many rows.
+    #[arg(long, env = "INDEX_MIN_ROWS", default_value_t = 10_000)]
+    pub index_min_rows: usize,
+    /// Rebuild the vector index once this many rows were added since it was built.
+    #[arg(long, env = "REINDEX_THRESHOLD", default_value_t = 5_000)]
+    pub reindex_threshold: usize,
+    /// Rebuild the vector index regardless of the thresholds.
+    #[arg(long)]
+    pub reindex: bool,
+    /// Number of IVF partitions, the square root of the row count if not set.
+    #[arg(long, env = "INDEX_PARTITIONS")]
+    pub num_partitions: Option<u32>,
+    /// Number of PQ sub-vectors, the embedding dimension divided by 16 if not set.
+    #[arg(long, env = "INDEX_SUB_VECTORS")]
+    pub num_sub_vectors: Option<u32>,
+}
+
+/// The embeddings table together with the model that produced its embeddings.
+pub struct VectorStore {
+    table: Table,
+    embedder: Box<dyn Embedder>,
+    schema: Arc<Schema>,
+    settings: TableSettings,
+    next_id: i32,
+    fts_path: PathBuf,
+    /// Loaded when the table is opened, `None` if it was never built.
+    fts: Option<FullTextIndex>,
+}
+
+impl VectorStore {
+    /// Open an existing table. Fails if the table was written with a different schema or
+    /// embedding model.
+    #[instrument(skip(embedder))]
+    pub async fn open(config: &Config, embedder: Box<dyn Embedder>) -> Result<Self> {
+        let conn = lancedb::connect(&config.uri).execute().await?;
+        let table = conn
+            .open_table(&config.table_name)
+            .execute()
+            .await
+            .with_context(|| format!("Failed to open table {}", config.table_name))?;
+        let schema = table.schema().await?;
+        let outdated = || {
+            format!(
+                "Table {} has an outdated schema, please re-ingest your documents",
+                config.table_name
+            )
+        };
+        let settings = TableSettings::from_metadata(&schema.metadata).with_context(outdated)?;
+        settings
+            .check_embedder(embedder.as_ref())
+            .with_context(|| format!("Cannot use table {}", config.table_name))?;
+        let expected =
+            embeddings_schema(embedding_size(embedder.as_ref())?, &extra_columns(&schema));
+        anyhow::ensure!(schema.fields() == expected.fields(), outdated());
+        let mut store = Self::new(config, table, embedder, schema, settings).await?;
+        if store.fts_path.exists() {
+            store.fts = Some(FullTextIndex::open(&store.fts_path)?);
+        }
+        Ok(store)
+    }
+
+    /// Create an empty table, replacing the existing one.
+    #[instrument(skip(embedder))]
+    pub async fn create(
+        config: &Config,
+        embedder: Box<dyn Embedder>,
+        chunking: ChunkingSettings,
+        metric: Metric,
+    ) -> Result<Self> {
+        let conn = lancedb::connect(&config.uri).execute().await?;
+        let settings = TableSettings::new(embedder.as_ref(), chunking, metric);
+        let schema = Arc::new(
+            embeddings_schema(embedding_size(embedder.as_ref())?, &[])
+                .with_metadata(settings.to_metadata()),
+        );
+        let table = create_or_overwrite_table(&conn, &config.table_name, schema.clone()).await?;
+        let mut store = Self::new(config, table, embedder, schema, settings).await?;
+        store.fts = Some(FullTextIndex::writer(&store.fts_path)?.finish()?);
+        Ok(store)
+    }
+
+    /// Number of rows in the configured table, `None` if there is no such table yet.
+    pub async fn row_count(config: &Config) -> Result<Option<usize>> {
+        let conn = lancedb::connect(&config.uri).execute().await?;
+        let names = conn.table_names().execute().await?;
+        if !names.contains(&config.table_name) {
+            return Ok(None);
+        }
+        let table = conn.open_table(&config.table_name).execute().await?;
+        Ok(Some(table.count_rows(None).await?))
+    }
+
+    async fn new(
+        config: &Config,
+        table: Table,
+        embedder: Box<dyn Embedder>,
+        schema: Arc<Schema>,
+        settings: TableSettings,
+    ) -> Result<Self> {
+        let next_id = read_max_id(&table).await? + 1;
+        Ok(VectorStore {
+            table,
+            embedder,
             schema,
+            settings,
+            next_id,
+            fts_path: FullTextIndex::path(&config.uri, &config.table_name)?,
+            fts: None,
+        })
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    /// Schema of the table, including the metadata columns added so far.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn embedder(&self) -> &dyn Embedder {
+        self.embedder.as_ref()
+    }
+
+    pub fn settings(&self) -> &TableSettings {
+        &self.settings
+    }
+
+    /// Embed and store chunks of text together with their provenance. Returns the new row ids.
+    pub async fn add_documents(
+        &mut self,
+        texts: Vec<String>,
+        metadata: Vec<ChunkMetadata>,
+    ) -> Result<Vec<i32>> {
+        let embeddings = vec![None; texts.len()];
+        self.add_with_embeddings(texts, metadata, embeddings).await
+    }
+
+    /// Like [`VectorStore::add_documents`], but only embeds the texts whose embedding is `None`.
+    pub async fn add_with_embeddings(
+        &mut self,
+        texts: Vec<String>,
+        metadata: Vec<ChunkMetadata>,
+        mut embeddings: Vec<Option<Embedding>>,
+    ) -> Result<Vec<i32>> {
+        anyhow::ensure!(
+            texts.len() == metadata.len() && texts.len() == embeddings.len(),
+            "Got {} texts, {} metadata entries and {} embeddings",
+            texts.len(),
+            metadata.len(),
+            embeddings.len()
+        );
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let missing: Vec<usize> = (0..texts.len())
+            .filter(|&i| embeddings[i].is_none())
+            .collect();
+        if !missing.is_empty() {
+            let to_embed: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+            let new_embeddings = self.embedder.embed_documents(to_embed).await?;
+            let dimension = self.embedder.dimension();
+            for (&i, mut embedding) in missing.iter().zip(new_embeddings) {
+                anyhow::ensure!(
+                    embedding.len() == dimension,
+                    "{} returned embeddings of size {}, expected {dimension}",
+                    self.embedder.model_id(),
+                    embedding.len()
+                );
+                if self.settings.metric.needs_unit_length() {
+                    normalize(&mut embedding);
+                }
+                embeddings[i] = Some(embedding);
+            }
         }
+
+        self.add_extra_columns(&metadata).await?;
+        let ids: Vec<i32> = (self.next_id..).take(texts.len()).collect();
+        self.next_id += i32::try_from(texts.len())?;
+        let chunks: Vec<Chunk> = ids
+            .iter()
+            .zip(texts)
+            .zip(metadata)
+            .map(|((&id, text), metadata)| Chunk { id, text, metadata })
+            .collect();
+        let embeddings = embeddings.into_iter().flatten().collect();
+        let batch = to_record_batch(
+            self.schema.clone(),
+            &chunks,
+            embeddings,
+            embedding_size(self.embedder.as_ref())?,
+        )?;
+        let batches = RecordBatchIterator::new(vec![Ok(batch)], self.schema.clone());
+        self.table.add(batches).execute().await?;
+        if let Some(fts) = &self.fts {
+            fts.add(chunks.iter().map(|chunk| (chunk.id, chunk.text.as_str())))?;
+        }
+        Ok(ids)
     }
+
+    /// Add a column for every metadata key the table doesn't have yet. Existing rows get null.
+    async fn add_extra_columns(&mut self, metadata: &[ChunkMetadata]) -> Result<()> {
+        let existing = extra_columns(&self.schema);
+        let new_keys: BTreeSet<&String> = metadata
+            .iter()
+            .flat_map(|m| m.extra.keys())
+            .filter(|key| !existing.contains(key))
+            .collect();
+        if new_keys.is_empty() {
+            return Ok(());
+        }
+        let mut columns = Vec::new();
+        for key in new_keys {
+            check_metadata_key(key)?;
+            info!("Adding metadata column {key}");
+            columns.push((key.clone(), "CAST(NULL AS STRING)".to_string()));
+        }
+        self.table
+            .add_columns(NewColumnTransform::SqlExpressions(columns), None)
+            .await?;
+        self.schema = self.table.schema().await?;
+        Ok(())
+    }
+
+    /// Find the chunks most relevant to `query`, best first. Returns fewer than `params.k`
+    /// hits, possibly none, if the table is small or the hits are too dissimilar.
+    pub async fn search(&self, query: &str, params: &SearchParams) -> Result<Vec<SearchHit>> {
+        let mut query_embedding = self.embedder.embed_query(query).await?;
+        if self.settings.metric.needs_unit_length() {
+            normalize(&mut query_embedding);
+        }
+        match params.mode {
+            SearchMode::Vector => self.vector_search(query_embedding, params).await,
+            SearchMode::Hybrid => self.hybrid_search(query, query_embedding, params).await,
+        }
+    }
+
+    async fn vector_search(
+        &self,
+        query_embedding: Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchHit>> {
+        let mut vector_query = self
+            .vector_query(query_embedding, params.k, params.filter.as_deref())?
+            .nprobes(params.nprobes);
+        if let Some(refine_factor) = params.refine_factor {
+            vector_query = vector_query.refine_factor(refine_factor);
+        }
+        let mut hits = self.execute_vector_query(vector_query).await?;
+        if let Some(min_similarity) = params.min_similarity {
+            let n_hits = hits.len();
+            hits.retain(|hit| hit.similarity >= min_similarity);
+            debug!(
+                "Dropped {} of {n_hits} hits below similarity {min_similarity}",
+                n_hits - hits.len()
+            );
+        }
+        Ok(hits)
+    }
+
+    /// Runs the vector and the keyword search concurrently and fuses both rankings.
+    async fn hybrid_search(
+        &self,
+        query: &str,
+        query_embedding: Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchHit>> {
+        let fts = self
+            .fts
+            .clone()
+            .ok_or_else(|| anyhow!("No full-text index, please re-ingest your documents"))?;
+        let (dense_hits, keyword_hits) = tokio::try_join!(
+            self.vector_search(query_embedding.clone(), params),
+            self.keyword_search(&fts, query, params.k, params.filter.as_deref())
+        )?;
+
+        let dense_ids: Vec<i32> = dense_hits.iter().map(|hit| hit.chunk.id).collect();
+        let keyword_ids: Vec<i32> = keyword_hits.iter().map(|(id, _)| *id).collect();
+        let fused = reciprocal_rank_fusion(&[
+            (&dense_ids, params.vector_weight),
+            (&keyword_ids, params.keyword_weight),
+        ]);
+
+        // Keyword hits are only ids, fetch their rows together with the distance to the query
+        let mut hits: HashMap<i32, SearchHit> = dense_hits
+            .into_iter()
+            .map(|hit| (hit.chunk.id, hit))
+            .collect();
+        let missing: Vec<String> = keyword_ids
+            .iter()
+            .filter(|id| !hits.contains_key(id))
+            .map(ToString::to_string)
+            .collect();
+        if !missing.is_empty() {
+            let mut predicate = format!("id IN ({})", missing.join(", "));
+            if let Some(filter) = &params.filter {
+                predicate = format!("{predicate} AND ({filter})");
+            }
+            // The rows are known, comparing with just them is exact and cheap
+            let vector_query = self
+                .vector_query(query_embedding, missing.len(), Some(&predicate))?
+                .bypass_vector_index();
+            let rows = self.execute_vector_query(vector_query).await?;
+            hits.extend(rows.into_iter().map(|hit| (hit.chunk.id, hit)));
+        }
+        for (id, keyword_score) in keyword_hits {
+            if let Some(hit) = hits.get_mut(&id) {
+                hit.keyword_score = Some(keyword_score);
+            }
+        }
+        Ok(fused
+            .into_iter()
+            .filter_map(|(id, score)| {
+                let mut hit = hits.remove(&id)?;
+                hit.score = score;
+                Some(hit)
+            })
+            .take(params.k)
+            .collect())
+    }
+
+    /// The best `k` BM25 hits for `query` among the rows matching the SQL `filter`. The index
+    /// ranks the whole table, so more hits are fetched until `k` of them match or the index
+    /// has no more.
+    async fn keyword_search(
+        &self,
+        fts: &FullTextIndex,
+        query: &str,
+        k: usize,
+        filter: Option<&str>,
+    ) -> Result<Vec<(i32, f32)>> {
+        let mut limit = if filter.is_some() {
+            k * KEYWORD_OVERFETCH
+        } else {
+            k
+        };
+        loop {
+            let index = fts.clone();
+            let keyword_query = query.to_string();
+            let hits =
+                tokio::task::spawn_blocking(move || index.search(&keyword_query, limit)).await??;
+            let Some(filter) = filter else {
+                return Ok(hits);
+            };
+            let exhausted = hits.len() < limit;
+            let matching = self.matching_ids(&hits, filter).await?;
+            let n_hits = hits.len();
+            let hits: Vec<(i32, f32)> = hits
+                .into_iter()
+                .filter(|(id, _)| matching.contains(id))
+                .take(k)
+                .collect();
+            if hits.len() == k || exhausted {
+                debug!("{} of {n_hits} keyword hits match the filter", hits.len());
+                return Ok(hits);
+            }
+            limit *= KEYWORD_OVERFETCH;
+        }
+    }
+
+    /// Ids of the `hits` whose rows match the SQL `filter`.
+    async fn matching_ids(&self, hits: &[(i32, f32)], filter: &str) -> Result<BTreeSet<i32>> {
+        if hits.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+        let ids: Vec<String> = hits.iter().map(|(id, _)| id.to_string()).collect();
+        let batches = self
+            .table
+            .query()
+            .only_if(format!("id IN ({}) AND ({filter})", ids.join(", ")))
+            .select(Select::columns(&["id"]))
+            .execute()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut matching = BTreeSet::new();
+        for batch in &batches {
+            matching.extend(column::<Int32Array>(batch, "id")?.values().iter().copied());
+        }
+        Ok(matching)
+    }
+
+    /// Nearest neighbors query using the metric of the table.
+    fn vector_query(
+        &self,
+        query_embedding: Embedding,
+        k: usize,
+        filter: Option<&str>,
+    ) -> Result<VectorQuery> {
+        let mut vector_query = self
+            .table
+            .query()
+            .nearest_to(query_embedding)
+            .context("Probably cannot convert input vector")?
+            .distance_type(self.settings.metric.distance_type())
+            .limit(k);
+        if let Some(filter) = filter {
+            vector_query = vector_query.only_if(filter);
+        }
+        Ok(vector_query)
+    }
+
+    async fn execute_vector_query(&self, vector_query: VectorQuery) -> Result<Vec<SearchHit>> {
+        let batches = vector_query
+            .execute()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut hits = Vec::new();
+        for batch in &batches {
+            hits.extend(hits_from_batch(batch, self.settings.metric)?);
+        }
+        Ok(hits)
+    }
+
+    /// Build the vector index once the table is large enough and rebuild it once enough rows
+    /// were added since. Returns whether the index was (re)built.
+    #[instrument(skip(self))]
+    pub async fn update_index(&self, params: &IndexParams) -> Result<bool> {
+        let n_rows = self.count(None).await?;
+        let min_rows = params.index_min_rows.max(MIN_INDEX_ROWS);
+        if n_rows < min_rows && !(params.reindex && n_rows >= MIN_INDEX_ROWS) {
+            debug!("Not indexing {n_rows} rows, searching them exhaustively is fast enough");
+            return Ok(false);
+        }
+        match self.unindexed_rows().await? {
+            Some(unindexed) if unindexed < params.reindex_threshold && !params.reindex => {
+                debug!("{unindexed} of {n_rows} rows are not indexed yet");
+                return Ok(false);
+            }
+            Some(unindexed) => info!("Rebuilding the vector index, {unindexed} rows are new"),
+            None => info!("Building the vector index over {n_rows} rows"),
+        }
+        let mut builder =
+            IvfPqIndexBuilder::default().distance_type(self.settings.metric.distance_type());
+        if let Some(num_partitions) = params.num_partitions {
+            builder = builder.num_partitions(num_partitions);
+        }
+        if let Some(num_sub_vectors) = params.num_sub_vectors {
+            builder = builder.num_sub_vectors(num_sub_vectors);
+        }
+        self.table
+            .create_index(&["embedding"], Index::IvfPq(builder))
+            .execute()
+            .await
+            .context("Failed to build the vector index")?;
+        Ok(true)
+    }
+
+    /// Rows added since the vector index was built, `None` if there is no index.
+    async fn unindexed_rows(&self) -> Result<Option<usize>> {
+        let table = self
+            .table
+            .as_native()
+            .ok_or_else(|| anyhow!("Vector indexes are only managed for local tables"))?;
+        let index = table
+            .load_indices()
+            .await?
+            .into_iter()
+            .find(|index| index.columns == ["embedding"]);
+        match index {
+            Some(index) => Ok(table.count_unindexed_rows(&index.index_uuid).await?),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the table has a full-text index. Rows added and deleted through the store keep
+    /// it up to date.
+    pub fn has_fts_index(&self) -> bool {
+        self.fts.is_some()
+    }
+
+    /// Rebuild the full-text index from the `text` column, e.g. if its directory was removed.
+    pub async fn create_fts_index(&mut self) -> Result<()> {
+        let mut batches = self
+            .table
+            .query()
+            .select(Select::columns(&["id", "text"]))
+            .execute()
+            .await?;
+        let mut writer = FullTextIndex::writer(&self.fts_path)?;
+        while let Some(batch) = batches.try_next().await? {
+            let ids = column::<Int32Array>(&batch, "id")?;
+            let texts = column::<StringArray>(&batch, "text")?;
+            for (id, text) in ids.values().iter().zip(texts.iter()) {
+                if let Some(text) = text {
+                    writer.add(*id, text)?;
+                }
+            }
+        }
+        self.fts = Some(writer.finish()?);
+        Ok(())
+    }
+
+    /// Delete all rows matching the SQL `predicate`, from the full-text index as well. Returns
+    /// the number of deleted rows.
+    pub async fn delete(&self, predicate: &str) -> Result<usize> {
+        let batches = self
+            .table
+            .query()
+            .only_if(predicate)
+            .select(Select::columns(&["id"]))
+            .execute()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut ids = Vec::new();
+        for batch in &batches {
+            ids.extend_from_slice(column::<Int32Array>(batch, "id")?.values());
+        }
+        if !ids.is_empty() {
+            self.table.delete(predicate).await?;
+            if let Some(fts) = &self.fts {
+                fts.delete(&ids)?;
+            }
+        }
+        Ok(ids.len())
+    }
+
+    pub async fn count(&self, filter: Option<&str>) -> Result<usize> {
+        Ok(self.table.count_rows(filter.map(String::from)).await?)
+    }
+
+    /// Map every stored source to the hash of the document version it was ingested from.
+    pub async fn document_hashes(&self) -> Result<HashMap<String, String>> {
+        let batches = self
+            .table
+            .query()
+            .select(Select::columns(&["source", "doc_hash"]))
+            .execute()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut hashes = HashMap::new();
+        for batch in &batches {
+            let sources = column::<StringArray>(batch, "source")?;
+            let doc_hashes = column::<StringArray>(batch, "doc_hash")?;
+            for (source, hash) in sources.iter().zip(doc_hashes.iter()) {
+                if let (Some(source), Some(hash)) = (source, hash) {
+                    hashes.insert(source.to_string(), hash.to_string());
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Map chunk hashes to their stored embedding for one source, so unchanged chunks of a
+    /// changed document don't have to be embedded again.
+    pub async fn chunk_embeddings(&self, source: &str) -> Result<HashMap<String, Embedding>> {
+        let batches = self
+            .table
+            .query()
+            .only_if(format!("source = {}", quote_literal(source)))
+            .select(Select::columns(&["chunk_hash", "embedding"]))
+            .execute()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut embeddings = HashMap::new();
+        for batch in &batches {
+            let hashes = column::<StringArray>(batch, "chunk_hash")?;
+            for (hash, embedding) in hashes.iter().zip(embeddings_from_batch(batch)?) {
+                if let Some(hash) = hash {
+                    embeddings.insert(hash.to_string(), embedding);
+                }
+            }
+        }
+        Ok(embeddings)
+    }
+}
+
+/// Arrow sizes fixed size lists with `i32`.
+fn embedding_size(embedder: &dyn Embedder) -> Result<i32> {
+    i32::try_from(embedder.dimension())
+        .with_context(|| format!("Embedding size {} is too large", embedder.dimension()))
+}
+
+fn hits_from_batch(batch: &RecordBatch, metric: Metric) -> Result<Vec<SearchHit>> {
+    let distances = column::<Float32Array>(batch, "_distance")
+        .map_err(|e| anyhow!("Search results carry no distance: {e}"))?;
+    Ok(chunks_from_batch(batch)?
+        .into_iter()
+        .zip(distances.values().iter())
+        .zip(embeddings_from_batch(batch)?)
+        .map(|((chunk, &distance), embedding)| {
+            let similarity = metric.similarity(distance);
+            SearchHit {
+                chunk,
+                distance,
+                keyword_score: None,
+                score: similarity,
+                similarity,
+                rerank_score: None,
+                embedding,
+            }
+        })
+        .collect())
 }
 
-impl Client {
-    pub async fn new(uri: String) -> lancedb::Result<Self> {
-        let conn = lancedb::connect(&uri).execute().await?;
-        Ok(Client { uri, conn })
+async fn read_max_id(table: &Table) -> Result<i32> {
+    let batches = table
+        .query()
+        .select(Select::columns(&["id"]))
+        .execute()
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    let max_id = batches
+        .iter()
+        .filter_map(|batch| {
+            let ids = column::<Int32Array>(batch, "id").ok()?;
+            ids.values().iter().max().copied()
+        })
+        .max();
+    Ok(max_id.unwrap_or(0))
+}
+
+pub async fn create_or_overwrite_table(
+    conn: &Connection,
+    name: &str,
+    schema: Arc<Schema>,
+) -> Result<Table> {
+    info!("Creating empty table {name}.");
+    let table = conn
+        .create_empty_table(name, schema)
+        .mode(CreateTableMode::Overwrite)
+        .execute()
+        .await
+        .context("Failed to create empty table {name}")?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_schema::{DataType, Field};
+    use std::fs;
+    use std::path::Path;
+
+    const DB_URI: &str = ".test_data/test_db";
+    const TABLE_NAME: &str = "test_table";
+
+    #[tokio::test]
+    async fn should_create_table_if_not_exists() {
+        let _ = fs::remove_dir_all(DB_URI);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("item", DataType::Utf8, true),
+        ]));
+        let conn = lancedb::connect(DB_URI).execute().await.unwrap();
+        let _ = create_or_overwrite_table(&conn, TABLE_NAME, schema)
+            .await
+            .unwrap();
+        let db_path = Path::new(DB_URI);
+        assert!(db_path.exists());
+        let _ = fs::remove_dir_all(DB_URI);
+    }
+
+    /// Embeds every text the same, so that only the keyword search tells chunks apart.
+    struct ConstantEmbedder;
+
+    #[async_trait::async_trait]
+    impl Embedder for ConstantEmbedder {
+        async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Embedding>> {
+            Ok(vec![vec![1.0, 0.0]; texts.len()])
+        }
+
+        async fn embed_query(&self, _query: &str) -> Result<Embedding> {
+            Ok(vec![1.0, 0.0])
+        }
+
+        fn dimension(&self) -> usize {
+            2
+        }
+
+        fn model_id(&self) -> &'static str {
+            "constant"
+        }
+
+        fn prefix_scheme(&self) -> PrefixScheme {
+            PrefixScheme::None
+        }
+
+        fn max_input_tokens(&self) -> Option<usize> {
+            None
+        }
+
+        fn tokenizer_file(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn should_apply_filter_to_keyword_hits() {
+        let uri = ".test_data/hybrid_db";
+        let _ = fs::remove_dir_all(uri);
+        let config = Config::new(uri.to_string(), TABLE_NAME.to_string());
+        let chunking = ChunkingSettings {
+            tokenizer: "constant".to_string(),
+            min_tokens: 100,
+            max_tokens: 100,
+            overlap_tokens: 0,
+            strategy: ChunkStrategy::Text,
+        };
+        let mut store = VectorStore::create(
+            &config,
+            Box::new(ConstantEmbedder),
+            chunking,
+            Metric::Cosine,
+        )
+        .await
+        .unwrap();
+        // The chunk of b.md ranks below all chunks of a.md in the keyword search
+        let mut texts = vec!["hash map hash map hash map".to_string(); 8];
+        let mut metadata: Vec<ChunkMetadata> =
+            (0..8).map(|i| ChunkMetadata::for_test("a.md", i)).collect();
+        texts.push("a hash map among many other words about vectors and strings".to_string());
+        metadata.push(ChunkMetadata::for_test("b.md", 0));
+        store.add_documents(texts, metadata).await.unwrap();
+
+        let params = SearchParams {
+            k: 1,
+            mode: SearchMode::Hybrid,
+            filter: Some("source = 'b.md'".to_string()),
+            ..SearchParams::default()
+        };
+        let hits = store.search("hash map", &params).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].chunk.metadata.source, "b.md");
+        assert!(hits[0].keyword_score.is_some());
+        let _ = fs::remove_dir_all(uri);
+    }
+
+    #[test]
+    fn should_roundtrip_settings_through_metadata() {
+        let settings = TableSettings {
+            model_id: "BAAI/bge-small-zh-v1.5".to_string(),
+            dimension: 512,
+            prefix_scheme: PrefixScheme::BgeZh,
+            chunking: ChunkingSettings {
+                tokenizer: "BAAI/bge-small-zh-v1.5".to_string(),
+                min_tokens: 256,
+                max_tokens: 510,
+                overlap_tokens: 51,
+                strategy: ChunkStrategy::Markdown,
+            },
+            metric: Metric::Cosine,
+        };
+        let metadata = settings.to_metadata();
+        assert_eq!(TableSettings::from_metadata(&metadata).unwrap(), settings);
+        assert!(TableSettings::from_metadata(&HashMap::new()).is_err());
     }
-    // pub async fn create_table(&self, name: &str, schema: Arc<Schema>) -> lancedb::Result<> {
-    //     let table = conn
-    //         .create_empty_table(table_name, schema.clone())
-    //         .execute()
-    //         .await?;
-    // }
 }
@@ -0,0 +1,304 @@
+use crate::record::quote_literal;
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_schema::{DataType, Schema};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Escapes literal `%` and `_` in LIKE patterns.
+const LIKE_ESCAPE: char = '^';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl Op {
+    /// Longer operators first, so that `>=` is not read as `>`.
+    const ALL: [(&'static str, Op); 6] = [
+        ("!=", Op::NotEq),
+        (">=", Op::GtEq),
+        ("<=", Op::LtEq),
+        ("=", Op::Eq),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::NotEq => "!=",
+            Op::Lt => "<",
+            Op::LtEq => "<=",
+            Op::Gt => ">",
+            Op::GtEq => ">=",
+        }
+    }
+}
+
+/// A single `column op value` comparison, e.g. `lang=en` or `modified_at>=2024-01-01`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub column: String,
+    pub op: Op,
+    pub value: String,
+}
+
+/// Restricts a search to chunks matching all conditions, e.g. `source=ch08* lang=en`.
+///
+/// `=` and `!=` on string columns accept `*` and `?` wildcards. On `source` a pattern also
+/// matches the file name alone, so `ch08*` finds `knowledge/ch08.md`. Timestamps are compared
+/// with dates like `2024-02-13` or RFC 3339 times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub conditions: Vec<Condition>,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    /// Conditions are separated by whitespace or commas. Values containing either go in double
+    /// quotes, e.g. `title="The Rust Book"`, with `\"` for a literal quote.
+    fn from_str(s: &str) -> Result<Self> {
+        let conditions = split_terms(s)?
+            .into_iter()
+            .map(|term| {
+                let (position, symbol, op) = Op::ALL
+                    .iter()
+                    .filter_map(|(symbol, op)| Some((term.find(symbol)?, *symbol, *op)))
+                    .min_by_key(|(position, symbol, _)| (*position, usize::MAX - symbol.len()))
+                    .ok_or_else(|| anyhow!("Expected a condition like key=value, got '{term}'"))?;
+                let column = &term[..position];
+                let value = &term[position + symbol.len()..];
+                if column.is_empty() || value.is_empty() {
+                    bail!("Expected a condition like key=value, got '{term}'");
+                }
+                Ok(Condition {
+                    column: column.to_string(),
+                    op,
+                    value: value.to_string(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Filter { conditions })
+    }
+}
+
+/// Split `s` on whitespace and commas outside of double quotes, dropping the quotes.
+fn split_terms(s: &str) -> Result<Vec<String>> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => term.push(
+                chars
+                    .next()
+                    .ok_or_else(|| anyhow!("Unterminated quote in filter '{s}'"))?,
+            ),
+            c if !quoted && (c.is_whitespace() || c == ',') => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quote in filter '{s}'");
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    Ok(terms)
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let conditions: Vec<String> = self
+            .conditions
+            .iter()
+            .map(|c| {
+                let value = if c
+                    .value
+                    .contains(|ch: char| ch.is_whitespace() || ",\"".contains(ch))
+                {
+                    format!("\"{}\"", c.value.replace('\\', "\\\\").replace('"', "\\\""))
+                } else {
+                    c.value.clone()
+                };
+                format!("{}{}{value}", c.column, c.op.as_str())
+            })
+            .collect();
+        write!(f, "{}", conditions.join(" "))
+    }
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    /// Translate into a `LanceDB` SQL predicate, checking columns and values against `schema`.
+    /// Returns `None` for an empty filter.
+    pub fn to_sql(&self, schema: &Schema) -> Result<Option<String>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let predicates = self
+            .conditions
+            .iter()
+            .map(|condition| condition_to_sql(condition, schema))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(predicates.join(" AND ")))
+    }
+}
+
+fn condition_to_sql(condition: &Condition, schema: &Schema) -> Result<String> {
+    let Condition { column, op, value } = condition;
+    let field = schema
+        .fields()
+        .iter()
+        .find(|field| field.name() == column && field.name() != "embedding")
+        .ok_or_else(|| {
+            let columns: Vec<&str> = schema
+                .fields()
+                .iter()
+                .map(|field| field.name().as_str())
+                .filter(|name| *name != "embedding")
+                .collect();
+            anyhow!("Unknown column '{column}', filterable are {columns:?}")
+        })?;
+    let op_sql = op.as_str();
+    match field.data_type() {
+        DataType::Utf8 if value.contains(['*', '?']) => {
+            let like = match op {
+                Op::Eq => "LIKE",
+                Op::NotEq => "NOT LIKE",
+                _ => bail!("Wildcards only work with = and !=, got '{column}{op_sql}{value}'"),
+            };
+            let pattern = like_pattern(value);
+            // `^` rather than a backslash, which some SQL dialects read as a string escape
+            let escape = if pattern.contains(LIKE_ESCAPE) {
+                format!(" ESCAPE '{LIKE_ESCAPE}'")
+            } else {
+                String::new()
+            };
+            let mut sql = format!("{column} {like} {}{escape}", quote_literal(&pattern));
+            if column == "source" {
+                let join = if *op == Op::Eq { "OR" } else { "AND" };
+                sql = format!(
+                    "({sql} {join} {column} {like} {}{escape})",
+                    quote_literal(&format!("%/{pattern}"))
+                );
+            }
+            Ok(sql)
+        }
+        DataType::Utf8 => Ok(format!("{column} {op_sql} {}", quote_literal(value))),
+        DataType::Timestamp(..) => {
+            let time = parse_time(value)?;
+            Ok(format!(
+                "{column} {op_sql} timestamp '{}'",
+                time.format("%Y-%m-%d %H:%M:%S%.3f")
+            ))
+        }
+        data_type if data_type.is_integer() => {
+            let number: i64 = value
+                .parse()
+                .with_context(|| format!("Column '{column}' needs an integer, got '{value}'"))?;
+            Ok(format!("{column} {op_sql} {number}"))
+        }
+        data_type => bail!("Cannot filter on column '{column}' of type {data_type}"),
+    }
+}
+
+/// Translate the `*` and `?` wildcards of `glob` into a LIKE pattern, escaping the characters
+/// LIKE would read as wildcards with [`LIKE_ESCAPE`].
+fn like_pattern(glob: &str) -> String {
+    let mut pattern = String::with_capacity(glob.len());
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' | LIKE_ESCAPE => {
+                pattern.push(LIKE_ESCAPE);
+                pattern.push(c);
+            }
+            c => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .with_context(|| format!("Expected a date like 2024-02-13, got '{value}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::embeddings_schema;
+
+    #[test]
+    fn should_translate_filters_to_sql() {
+        let schema = embeddings_schema(2, &["lang".to_string()]);
+        let sql = |filter: &str| {
+            let filter: Filter = filter.parse().unwrap();
+            filter.to_sql(&schema).unwrap().unwrap()
+        };
+        assert_eq!(
+            sql("source=ch08*"),
+            "(source LIKE 'ch08%' OR source LIKE '%/ch08%')"
+        );
+        assert_eq!(
+            sql("lang=en, chunk_index>=3"),
+            "lang = 'en' AND chunk_index >= 3"
+        );
+        assert_eq!(
+            sql("modified_at<2024-02-13"),
+            "modified_at < timestamp '2024-02-13 00:00:00.000'"
+        );
+        assert_eq!(sql("title!=it's"), "title != 'it''s'");
+        assert_eq!(
+            sql("title=\"The Rust Book\", lang=en"),
+            "title = 'The Rust Book' AND lang = 'en'"
+        );
+        assert_eq!(sql("title=\"say \\\"hi\\\"\""), "title = 'say \"hi\"'");
+        assert_eq!(sql("lang=en_*"), "lang LIKE 'en^_%' ESCAPE '^'");
+        assert_eq!(
+            sql("source!=100%_*"),
+            "(source NOT LIKE '100^%^_%' ESCAPE '^' AND source NOT LIKE '%/100^%^_%' ESCAPE '^')"
+        );
+        let filter: Filter = "title=\"a, b\" lang=en".parse().unwrap();
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+        assert!(""
+            .parse::<Filter>()
+            .unwrap()
+            .to_sql(&schema)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_filters() {
+        let schema = embeddings_schema(2, &[]);
+        let to_sql = |filter: &str| filter.parse::<Filter>()?.to_sql(&schema);
+        assert!(to_sql("lang").is_err());
+        assert!(to_sql("lang=en").is_err());
+        assert!(to_sql("chunk_index=one").is_err());
+        assert!(to_sql("source>ch*").is_err());
+        assert!(to_sql("title=\"The Rust").is_err());
+    }
+}
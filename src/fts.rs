@@ -0,0 +1,201 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, Value, INDEXED, STORED, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, TantivyDocument, Term};
+use tracing::{debug, info, instrument};
+
+/// Memory the index writer may use before flushing segments to disk.
+const WRITER_HEAP_BYTES: usize = 50_000_000;
+
+/// BM25 index over the `text` column, keyed by row id.
+///
+/// This version of `LanceDB` has no full-text search in Rust, so the index lives in a tantivy
+/// directory next to the table.
+#[derive(Clone)]
+pub struct FullTextIndex {
+    index: Index,
+    reader: IndexReader,
+    id_field: Field,
+    text_field: Field,
+}
+
+impl FullTextIndex {
+    /// Directory of the index belonging to `table_name` in the database at `uri`. Fails for
+    /// object stores like `s3://`, the index needs a local directory.
+    pub fn path(uri: &str, table_name: &str) -> Result<PathBuf> {
+        let local = match uri.split_once("://") {
+            None => uri,
+            Some(("file", path)) => path,
+            Some(_) => bail!("Full-text search needs a local database directory, got {uri}"),
+        };
+        Ok(Path::new(local).join(format!("{table_name}.fts")))
+    }
+
+    /// Build a fresh index from `(id, text)` rows, replacing an existing one at `path`.
+    pub fn create<'a>(path: &Path, rows: impl IntoIterator<Item = (i32, &'a str)>) -> Result<Self> {
+        let mut writer = FullTextIndex::writer(path)?;
+        for (id, text) in rows {
+            writer.add(id, text)?;
+        }
+        writer.finish()
+    }
+
+    /// Start a fresh index at `path`, replacing an existing one. Rows are added one by one, so
+    /// the table doesn't have to fit into memory.
+    #[instrument]
+    pub fn writer(path: &Path) -> Result<FullTextIndexWriter> {
+        if path.exists() {
+            fs::remove_dir_all(path)
+                .with_context(|| format!("Failed to remove old index {}", path.display()))?;
+        }
+        fs::create_dir_all(path)?;
+        let mut builder = Schema::builder();
+        let id_field = builder.add_i64_field("id", INDEXED | STORED);
+        let text_field = builder.add_text_field("text", TEXT);
+        let index = Index::create_in_dir(path, builder.build())?;
+        Ok(FullTextIndexWriter {
+            writer: index.writer(WRITER_HEAP_BYTES)?,
+            index,
+            id_field,
+            text_field,
+            n_rows: 0,
+        })
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let index = Index::open_in_dir(path).with_context(|| {
+            format!(
+                "No full-text index at {}, please re-ingest your documents",
+                path.display()
+            )
+        })?;
+        Self::new(index)
+    }
+
+    fn new(index: Index) -> Result<Self> {
+        let schema = index.schema();
+        Ok(FullTextIndex {
+            reader: index.reader()?,
+            id_field: schema.get_field("id")?,
+            text_field: schema.get_field("text")?,
+            index,
+        })
+    }
+
+    /// Index `(id, text)` rows in addition to the existing ones.
+    pub fn add<'a>(&self, rows: impl IntoIterator<Item = (i32, &'a str)>) -> Result<()> {
+        let writer: IndexWriter = self.index.writer(WRITER_HEAP_BYTES)?;
+        for (id, text) in rows {
+            writer.add_document(doc!(self.id_field => i64::from(id), self.text_field => text))?;
+        }
+        self.commit(writer)
+    }
+
+    /// Remove the rows with `ids` from the index.
+    pub fn delete(&self, ids: &[i32]) -> Result<()> {
+        let writer: IndexWriter = self.index.writer(WRITER_HEAP_BYTES)?;
+        for &id in ids {
+            writer.delete_term(Term::from_field_i64(self.id_field, i64::from(id)));
+        }
+        self.commit(writer)
+    }
+
+    /// Commit the changes of `writer` and make them visible to the next search.
+    fn commit(&self, mut writer: IndexWriter) -> Result<()> {
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Ids of the `k` best matching rows with their BM25 score, best first. Query syntax errors
+    /// are ignored, so arbitrary user questions like `What is Box<dyn Trait>?` can be passed.
+    pub fn search(&self, query: &str, k: usize) -> Result<Vec<(i32, f32)>> {
+        let parser = QueryParser::for_index(&self.index, vec![self.text_field]);
+        let (query, errors) = parser.parse_query_lenient(query);
+        if !errors.is_empty() {
+            debug!("Ignored query syntax errors: {errors:?}");
+        }
+        let searcher = self.reader.searcher();
+        searcher
+            .search(&query, &TopDocs::with_limit(k))?
+            .into_iter()
+            .map(|(score, address)| {
+                let doc: TantivyDocument = searcher.doc(address)?;
+                let id = doc
+                    .get_first(self.id_field)
+                    .and_then(|value| value.as_i64())
+                    .ok_or_else(|| anyhow!("Indexed document without id"))?;
+                Ok((i32::try_from(id)?, score))
+            })
+            .collect()
+    }
+}
+
+/// Adds rows to a new [`FullTextIndex`], see [`FullTextIndex::writer`].
+pub struct FullTextIndexWriter {
+    index: Index,
+    writer: IndexWriter,
+    id_field: Field,
+    text_field: Field,
+    n_rows: usize,
+}
+
+impl FullTextIndexWriter {
+    pub fn add(&mut self, id: i32, text: &str) -> Result<()> {
+        self.writer
+            .add_document(doc!(self.id_field => i64::from(id), self.text_field => text))?;
+        self.n_rows += 1;
+        Ok(())
+    }
+
+    /// Commit the rows and open the index for searching.
+    pub fn finish(mut self) -> Result<FullTextIndex> {
+        self.writer.commit()?;
+        info!("Indexed {} chunks for full-text search", self.n_rows);
+        FullTextIndex::new(self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_find_exact_identifiers() {
+        let path = Path::new(".test_data/test_fts");
+        let rows = [
+            (
+                1,
+                "Use a trait object like Box<dyn Trait> for dynamic dispatch.",
+            ),
+            (2, "Ownership rules: each value has a single owner."),
+            (3, "Error E0382 means a value was used after it was moved."),
+        ];
+        let index = FullTextIndex::create(path, rows).unwrap();
+        let ids = |query| -> Vec<i32> {
+            let hits = index.search(query, 2).unwrap();
+            hits.into_iter().map(|(id, _)| id).collect()
+        };
+        assert_eq!(ids("What does E0382 mean?")[0], 3);
+        assert_eq!(ids("Box<dyn Trait>")[0], 1);
+
+        index.delete(&[3]).unwrap();
+        index
+            .add([(4, "The compiler reports E0382 for moved values.")])
+            .unwrap();
+        assert_eq!(ids("What does E0382 mean?"), vec![4]);
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn should_only_accept_local_databases() {
+        assert_eq!(
+            FullTextIndex::path("file:///data/db", "chunks").unwrap(),
+            Path::new("/data/db/chunks.fts")
+        );
+        assert!(FullTextIndex::path("s3://bucket/db", "chunks").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::chunk::load_tokenizer;
+use crate::llm::{ChatMessage, LlmBackend};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+use tracing::{info, warn};
+
+/// Tokens the chat template adds around every message for the role and delimiters.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// Assumed context window of models whose server doesn't tell.
+const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+/// Rough number of characters per token, for counting without a tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and an \
+assistant in a few sentences. Keep names, facts and open questions, leave out pleasantries.";
+
+/// How the conversation is fitted into the context window of the chat model.
+#[derive(Debug, Clone, clap::Args)]
+pub struct HistoryConfig {
+    /// Path of the chat model's `tokenizer.json`, or of a directory containing one, that
+    /// counts the tokens of the conversation. Defaults to the embedding model's tokenizer.
+    #[arg(long, env = "CHAT_TOKENIZER")]
+    pub chat_tokenizer: Option<PathBuf>,
+    /// Tokens of the context window kept free for the reply.
+    #[arg(long, env = "REPLY_TOKENS", default_value_t = 512)]
+    pub reply_tokens: usize,
+    /// Summarize turns that no longer fit into the context window instead of dropping them.
+    #[arg(long, env = "SUMMARIZE_HISTORY")]
+    pub summarize_history: bool,
+}
+
+/// Counts the tokens of chat messages.
+pub enum TokenCounter {
+    Tokenizer(Box<Tokenizer>),
+    /// Guesses from the length of the text, for models without a local tokenizer.
+    Estimate,
+}
+
+impl TokenCounter {
+    /// The tokenizer at `path`, else the one in `fallback`, else an estimate.
+    pub fn load(path: Option<&Path>, fallback: Option<&Path>) -> Result<Self> {
+        let Some(file) = path.or(fallback) else {
+            warn!("No tokenizer for the chat model, estimating the conversation length");
+            return Ok(TokenCounter::Estimate);
+        };
+        if path.is_none() {
+            warn!(
+                "Counting chat tokens with the embedding model's tokenizer, pass \
+                 --chat-tokenizer for exact counts"
+            );
+        }
+        Ok(TokenCounter::Tokenizer(Box::new(load_tokenizer(file)?)))
+    }
+
+    pub fn count(&self, text: &str) -> Result<usize> {
+        match self {
+            TokenCounter::Tokenizer(tokenizer) => Ok(tokenizer
+                .encode(text, false)
+                .map_err(|e| anyhow!("Failed to count tokens: {e}"))?
+                .len()),
+            TokenCounter::Estimate => Ok(text.chars().count().div_ceil(CHARS_PER_TOKEN)),
+        }
+    }
+
+    fn count_messages(&self, messages: &[ChatMessage]) -> Result<usize> {
+        messages.iter().try_fold(0, |total, message| {
+            Ok(total + self.count(&message.content)? + MESSAGE_OVERHEAD_TOKENS)
+        })
+    }
+}
+
+/// A question and the answer to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+/// The conversation so far, sent along with every new message within a token budget.
+///
+/// Only the newest user message carries the retrieved CONTEXT, past turns keep the bare
+/// question. Turns that don't fit into the budget anymore are dropped oldest first, or folded
+/// into a summary.
+pub struct ChatHistory {
+    system: String,
+    turns: Vec<Turn>,
+    summary: Option<String>,
+    counter: TokenCounter,
+    budget: usize,
+}
+
+impl ChatHistory {
+    /// `budget` is the number of tokens the prompt may take.
+    pub fn new(system: impl Into<String>, counter: TokenCounter, budget: usize) -> Self {
+        ChatHistory {
+            system: system.into(),
+            turns: Vec::new(),
+            summary: None,
+            counter,
+            budget,
+        }
+    }
+
+    /// Budget the context window of `llm` minus `config.reply_tokens` for the prompt.
+    pub async fn for_model(
+        system: impl Into<String>,
+        counter: TokenCounter,
+        llm: &dyn LlmBackend,
+        config: &HistoryConfig,
+    ) -> Result<Self> {
+        let context_length = llm.context_length().await?.unwrap_or_else(|| {
+            warn!(
+                "Context window of {} is unknown, assuming {DEFAULT_CONTEXT_LENGTH} tokens",
+                llm.model()
+            );
+            DEFAULT_CONTEXT_LENGTH
+        });
+        anyhow::ensure!(
+            config.reply_tokens < context_length,
+            "Reserving {} tokens for the reply leaves no room in the context window of {} tokens",
+            config.reply_tokens,
+            context_length
+        );
+        info!(
+            "Context window of {} tokens, {} of them for the reply",
+            context_length, config.reply_tokens
+        );
+        Ok(ChatHistory::new(
+            system,
+            counter,
+            context_length - config.reply_tokens,
+        ))
+    }
+
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// The messages to send for the new `user_message`: the system prompt followed by the
+    /// summary of dropped turns, the past turns and `user_message`.
+    pub fn prompt(&self, user_message: &str) -> Vec<ChatMessage> {
+        let system = match &self.summary {
+            Some(summary) => format!(
+                "{}\n\nSummary of the earlier conversation: {summary}",
+                self.system
+            ),
+            None => self.system.clone(),
+        };
+        let mut messages = vec![ChatMessage::system(system)];
+        for turn in &self.turns {
+            messages.push(ChatMessage::user(format!("QUESTION: {}", turn.question)));
+            messages.push(ChatMessage::assistant(turn.answer.as_str()));
+        }
+        messages.push(ChatMessage::user(user_message));
+        messages
+    }
+
+    /// Tokens the prompt for `user_message` takes.
+    pub fn prompt_tokens(&self, user_message: &str) -> Result<usize> {
+        self.counter.count_messages(&self.prompt(user_message))
+    }
+
+    /// Drop the oldest turns until the prompt for `user_message` fits into the budget, and
+    /// return them. The system prompt and `user_message` are always kept.
+    pub fn fit(&mut self, user_message: &str) -> Result<Vec<Turn>> {
+        let mut dropped = 0;
+        let mut tokens = self.prompt_tokens(user_message)?;
+        while tokens > self.budget && dropped < self.turns.len() {
+            let turn = &self.turns[dropped];
+            let question = format!("QUESTION: {}", turn.question);
+            tokens -= self.counter.count(&question)?
+                + self.counter.count(&turn.answer)?
+                + 2 * MESSAGE_OVERHEAD_TOKENS;
+            dropped += 1;
+        }
+        if tokens > self.budget {
+            warn!(
+                "The prompt takes {tokens} tokens, more than the {} available, the model may \
+                 cut it off",
+                self.budget
+            );
+        }
+        Ok(self.turns.drain(..dropped).collect())
+    }
+
+    /// Fold `dropped` turns into the summary of the earlier conversation.
+    pub async fn summarize(&mut self, llm: &dyn LlmBackend, dropped: &[Turn]) -> Result<()> {
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        let transcript = match &self.summary {
+            Some(summary) => format!("{summary}\n\n{}", transcript(dropped)),
+            None => transcript(dropped),
+        };
+        let summary = llm
+            .chat(&[
+                ChatMessage::system(SUMMARY_PROMPT),
+                ChatMessage::user(transcript),
+            ])
+            .await?;
+        info!("Summarized {} turns of the conversation", dropped.len());
+        self.summary = Some(summary.trim().to_string());
+        Ok(())
+    }
+
+    pub fn push(&mut self, question: impl Into<String>, answer: impl Into<String>) {
+        self.turns.push(Turn {
+            question: question.into(),
+            answer: answer.into(),
+        });
+    }
+}
+
+/// Write `turns` as a plain "User: ... Assistant: ..." transcript for prompts that talk about
+/// the conversation.
+pub fn transcript(turns: &[Turn]) -> String {
+    let mut transcript = String::new();
+    for turn in turns {
+        transcript.push_str("User: ");
+        transcript.push_str(&turn.question);
+        transcript.push_str("\nAssistant: ");
+        transcript.push_str(&turn.answer);
+        transcript.push('\n');
+    }
+    transcript
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::Role;
+
+    #[test]
+    fn should_only_send_context_with_the_newest_question() {
+        let mut history = ChatHistory::new("Be concise.", TokenCounter::Estimate, 1000);
+        history.push("What is a vector?", "A growable list.");
+        let messages = history.prompt("QUESTION: And a map?\n\nCONTEXT: [1] book.txt");
+        let roles: Vec<Role> = messages.iter().map(|message| message.role).collect();
+        assert_eq!(
+            roles,
+            vec![Role::System, Role::User, Role::Assistant, Role::User]
+        );
+        assert_eq!(messages[1].content, "QUESTION: What is a vector?");
+        assert!(messages[3].content.contains("CONTEXT"));
+
+        history.summary = Some("The user asked about strings.".to_string());
+        let messages = history.prompt("QUESTION: And a set?");
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, Role::System);
+        assert!(messages[0].content.starts_with("Be concise."));
+        assert!(messages[0]
+            .content
+            .ends_with("The user asked about strings."));
+    }
+
+    #[test]
+    fn should_write_turns_as_transcript() {
+        let turns = [Turn {
+            question: "What is a vector?".to_string(),
+            answer: "A growable list.".to_string(),
+        }];
+        assert_eq!(
+            transcript(&turns),
+            "User: What is a vector?\nAssistant: A growable list.\n"
+        );
+    }
+
+    #[test]
+    fn should_drop_oldest_turns_beyond_the_budget() {
+        let question = "QUESTION: Which turn is this?";
+        let mut history = ChatHistory::new("Be concise.", TokenCounter::Estimate, 1000);
+        for turn in 0..3 {
+            history.push(format!("Turn {turn}?"), "x".repeat(200));
+        }
+        let budget = history.prompt_tokens(question).unwrap() - 1;
+        history.budget = budget;
+        let dropped = history.fit(question).unwrap();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].question, "Turn 0?");
+        assert_eq!(history.turns().len(), 2);
+        assert!(history.prompt_tokens(question).unwrap() <= budget);
+
+        history.budget = 0;
+        assert_eq!(history.fit(question).unwrap().len(), 2);
+        assert_eq!(history.prompt(question).len(), 2);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::chunk::{ChunkStrategy, Chunker, HEADING_SEPARATOR};
+use crate::loader::loader_for;
+use crate::record::ChunkMetadata;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tracing::{debug, instrument};
+use walkdir::WalkDir;
+
+const MAX_TITLE_CHARS: usize = 200;
+const PART_SEPARATOR: &str = "\n\n";
+
+/// Decides which files found while walking a directory get ingested.
+///
+/// Glob patterns are matched against the path relative to the walked directory and against the
+/// bare file name, so both `ch08*` and `**/src/*.md` work as expected.
+#[derive(Debug)]
+pub struct DocumentFilter {
+    extensions: Vec<String>,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl DocumentFilter {
+    /// An empty `extensions` list accepts every extension, an empty `include` list every path.
+    pub fn new(extensions: &[String], include: &[String], exclude: &[String]) -> Result<Self> {
+        let extensions = extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect();
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_globset(include)?)
+        };
+        let exclude = build_globset(exclude)?;
+        Ok(DocumentFilter {
+            extensions,
+            include,
+            exclude,
+        })
+    }
+
+    pub fn is_match(&self, path: &Path, root: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let file_name = path.file_name().map_or(relative, Path::new);
+        let matches = |set: &GlobSet| set.is_match(relative) || set.is_match(file_name);
+
+        let extension_ok = self.extensions.is_empty()
+            || path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| self.extensions.contains(&ext.to_lowercase()));
+        let include_ok = self.include.as_ref().is_none_or(matches);
+        extension_ok && include_ok && !matches(&self.exclude)
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob '{pattern}'"))?);
+    }
+    builder.build().context("Failed to build glob set")
+}
+
+/// Collect every document below `paths` that passes `filter`, sorted and without duplicates.
+///
+/// Directories are walked recursively. Files that are passed explicitly are always returned,
+/// the filter only applies to what is found inside directories.
+#[instrument(skip(filter))]
+pub fn discover_documents(paths: &[PathBuf], filter: &DocumentFilter) -> Result<Vec<PathBuf>> {
+    let mut documents = BTreeSet::new();
+    for root in paths {
+        if root.is_file() {
+            documents.insert(root.clone());
+            continue;
+        }
+        anyhow::ensure!(root.is_dir(), "{} does not exist", root.display());
+        for entry in WalkDir::new(root).follow_links(true) {
+            let entry =
+                entry.with_context(|| format!("Failed to read contents of {}", root.display()))?;
+            if entry.file_type().is_file() && filter.is_match(entry.path(), root) {
+                debug!("Found document {}", entry.path().display());
+                documents.insert(entry.into_path());
+            }
+        }
+    }
+    Ok(documents.into_iter().collect())
+}
+
+/// Name a file or directory is stored under: its path relative to the working directory, or
+/// its absolute path if it lies outside, so that `./knowledge` and `knowledge` name the same
+/// documents in every run.
+pub fn source_name(path: &Path) -> Result<String> {
+    let path =
+        fs::canonicalize(path).with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let working_dir = std::env::current_dir()?.canonicalize()?;
+    let name = path.strip_prefix(&working_dir).unwrap_or(&path);
+    Ok(name.display().to_string())
+}
+
+/// Whether `source` is the file `root` or lies in the directory `root`, both named by
+/// [`source_name`].
+pub fn is_within(source: &str, root: &str) -> bool {
+    Path::new(source).starts_with(root)
+}
+
+/// A document read from disk, ready to be split.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub source: String,
+    pub title: String,
+    pub modified_at: DateTime<Utc>,
+    /// Extracted text, with the parts separated by blank lines.
+    pub content: String,
+    /// Pages or chapters of `content` that are chunked separately.
+    pub parts: Vec<DocumentPart>,
+    /// Hex encoded SHA-256 of `content`, used to skip unchanged documents on re-ingestion.
+    pub hash: String,
+}
+
+/// A page or chapter of a [`Document`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentPart {
+    /// Byte range of the part in the document's content.
+    pub range: Range<usize>,
+    pub page: Option<u32>,
+    /// Heading the chunks of the part are nested under, e.g. an EPUB chapter title.
+    pub heading: Option<String>,
+}
+
+impl Document {
+    /// Read a file in any format supported by [`crate::loader`], picked by its extension or
+    /// content.
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let loaded = loader_for(path, &bytes)
+            .and_then(|loader| loader.load(&bytes))
+            .with_context(|| format!("Failed to load {}", path.display()))?;
+        let modified_at = fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .map_or(DateTime::UNIX_EPOCH, DateTime::<Utc>::from);
+
+        let mut content = String::new();
+        let mut parts = Vec::with_capacity(loaded.parts.len());
+        for part in loaded.parts {
+            if !content.is_empty() {
+                content.push_str(PART_SEPARATOR);
+            }
+            let start = content.len();
+            content.push_str(&part.text);
+            parts.push(DocumentPart {
+                range: start..content.len(),
+                page: part.page,
+                heading: part.heading,
+            });
+        }
+        let title = loaded
+            .title
+            .or_else(|| title_from_content(&content))
+            .unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+        Ok(Document {
+            source: source_name(path)?,
+            title,
+            modified_at,
+            hash: sha256_hex(&content),
+            content,
+            parts,
+        })
+    }
+
+    /// Split every part of the document into chunks of at most `max_tokens` tokens, keeping
+    /// track of where each chunk is located in the extracted text and how much it overlaps
+    /// the previous one. Chunks are produced lazily.
+    pub fn split<'a>(
+        &'a self,
+        chunker: &'a Chunker,
+        ingested_at: DateTime<Utc>,
+    ) -> impl Iterator<Item = (String, ChunkMetadata)> + 'a {
+        let mut chars_before = 0;
+        let mut last_byte = 0;
+        self.parts
+            .iter()
+            .flat_map(move |part| {
+                chunker
+                    .chunks(&self.content[part.range.clone()], &self.source)
+                    .into_iter()
+                    .map(move |span| (part, span))
+            })
+            .enumerate()
+            .map(move |(chunk_index, (part, span))| {
+                let byte_start = part.range.start + span.range.start;
+                let text = &self.content[byte_start..part.range.start + span.range.end];
+                chars_before += self.content[last_byte..byte_start].chars().count();
+                last_byte = byte_start;
+                let char_len = text.chars().count();
+                let mut heading_path = span.heading_path;
+                // Chapters usually start with their title as heading already
+                if let Some(heading) = &part.heading {
+                    if heading_path.first() != Some(heading) {
+                        heading_path.insert(0, heading.clone());
+                    }
+                }
+                let metadata = ChunkMetadata {
+                    source: self.source.clone(),
+                    title: self.title.clone(),
+                    heading_path: heading_path.join(HEADING_SEPARATOR),
+                    byte_start: byte_start as u64,
+                    byte_end: (byte_start + text.len()) as u64,
+                    char_start: chars_before as u64,
+                    char_end: (chars_before + char_len) as u64,
+                    chunk_index: u32::try_from(chunk_index).expect("Fewer than u32::MAX chunks"),
+                    overlap_bytes: span.overlap as u64,
+                    page: part.page,
+                    doc_hash: self.hash.clone(),
+                    chunk_hash: sha256_hex(text),
+                    modified_at: self.modified_at,
+                    ingested_at,
+                    extra: BTreeMap::new(),
+                };
+                (text.to_string(), metadata)
+            })
+    }
+}
+
+pub fn sha256_hex(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Chunking options. Whatever is left out is derived from the embedding model, see
+/// [`crate::chunk::init_chunker`].
+#[derive(Debug, Clone, clap::Args)]
+pub struct ChunkingConfig {
+    /// Path of a `tokenizer.json`, or of a directory containing one, that measures the chunk
+    /// size. Defaults to the tokenizer of the fastembed embedding model.
+    #[arg(long, env = "TOKENIZER")]
+    pub tokenizer: Option<PathBuf>,
+    /// Chunks are cut once they reach this many tokens, unless the next piece of text still
+    /// fits. Smaller values give more uniform chunks. Defaults to half of `--max-tokens`.
+    #[arg(long, env = "MIN_TOKENS")]
+    pub min_tokens: Option<usize>,
+    /// Upper limit of the chunk size in tokens, overlap included. Defaults to the maximum
+    /// sequence length of the embedding model.
+    #[arg(long, env = "MAX_TOKENS")]
+    pub max_tokens: Option<usize>,
+    /// Repeat up to this many tokens of the previous chunk at the start of a chunk, so that
+    /// ideas spanning a chunk boundary are found in one piece. Defaults to a tenth of
+    /// `--max-tokens`.
+    #[arg(long, env = "OVERLAP_TOKENS")]
+    pub overlap_tokens: Option<usize>,
+    /// How documents are cut into chunks. `auto` splits Markdown on headings, Rust sources on
+    /// items and everything else as plain text.
+    #[arg(long = "chunker", env = "CHUNK_STRATEGY", value_enum, default_value_t)]
+    pub strategy: ChunkStrategy,
+    /// Only warn instead of failing if chunks may be longer than the embedding model's
+    /// maximum sequence length. The model silently ignores the rest of such chunks.
+    #[arg(long)]
+    pub allow_truncation: bool,
+}
+
+/// How documents are split into chunks. Chunks of a table must all be split the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkingSettings {
+    /// Tokenizer that measures the chunk size, the id of the embedding model or the path of
+    /// a `tokenizer.json`.
+    pub tokenizer: String,
+    pub min_tokens: usize,
+    /// Upper limit of the chunk size in tokens, overlap included.
+    pub max_tokens: usize,
+    pub overlap_tokens: usize,
+    pub strategy: ChunkStrategy,
+}
+
+impl ChunkingSettings {
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.min_tokens <= self.max_tokens,
+            "The minimum chunk size {} exceeds the maximum {}",
+            self.min_tokens,
+            self.max_tokens
+        );
+        anyhow::ensure!(
+            self.overlap_tokens < self.max_tokens,
+            "The overlap of {} tokens leaves no room in chunks of at most {} tokens",
+            self.overlap_tokens,
+            self.max_tokens
+        );
+        Ok(())
+    }
+}
+
+/// What an ingestion run changed in the embeddings table, counted in rows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IngestSummary {
+    /// Chunks that had to be embedded.
+    pub added: usize,
+    /// Chunks whose stored embedding was still valid.
+    pub kept: usize,
+    /// Chunks of removed or changed documents that are gone now.
+    pub removed: usize,
+    /// Documents that could not be read.
+    pub skipped: usize,
+}
+
+impl fmt::Display for IngestSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows added, {} kept, {} removed, {} documents skipped",
+            self.added, self.kept, self.removed, self.skipped
+        )
+    }
+}
+
+/// Use the first non-empty line as title, Markdown heading markers stripped.
+fn title_from_content(content: &str) -> Option<String> {
+    content
+        .lines()
+        .map(|line| line.trim().trim_start_matches('#').trim())
+        .find(|line| !line.is_empty())
+        .map(|line| line.chars().take(MAX_TITLE_CHARS).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(extensions: &[&str], include: &[&str], exclude: &[&str]) -> DocumentFilter {
+        let owned = |v: &[&str]| v.iter().map(ToString::to_string).collect::<Vec<_>>();
+        DocumentFilter::new(&owned(extensions), &owned(include), &owned(exclude)).unwrap()
+    }
+
+    #[test]
+    fn should_filter_by_extension_and_globs() {
+        let root = Path::new("docs");
+        let filter = filter(&["txt", ".md"], &["ch0*"], &["**/drafts/**"]);
+        assert!(filter.is_match(Path::new("docs/book/ch08.md"), root));
+        assert!(filter.is_match(Path::new("docs/ch01.TXT"), root));
+        assert!(!filter.is_match(Path::new("docs/ch01.pdf"), root));
+        assert!(!filter.is_match(Path::new("docs/appendix.md"), root));
+        assert!(!filter.is_match(Path::new("docs/drafts/ch02.md"), root));
+    }
+
+    #[test]
+    fn should_name_sources_the_same_for_every_spelling() {
+        let dir = Path::new(".test_data/sources");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("a.txt"), "A").unwrap();
+        let name = source_name(Path::new(".test_data/sources/a.txt")).unwrap();
+        assert_eq!(
+            name,
+            Path::new(".test_data/sources/a.txt").display().to_string()
+        );
+        assert_eq!(
+            source_name(Path::new("./.test_data/sources/../sources/a.txt")).unwrap(),
+            name
+        );
+        let root = source_name(Path::new("./.test_data/sources/")).unwrap();
+        assert!(is_within(&name, &root));
+        assert!(!is_within(&name, ".test_data/sourc"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn should_take_title_from_first_line() {
+        let title = title_from_content("\n\n# Ch. 8 Common Collections\nVectors ...");
+        assert_eq!(title.as_deref(), Some("Ch. 8 Common Collections"));
+        assert_eq!(title_from_content("  \n"), None);
+    }
+
+    #[test]
+    fn should_accept_everything_without_rules() {
+        let filter = filter(&[], &[], &[]);
+        assert!(filter.is_match(Path::new("a/b/c"), Path::new("a")));
+    }
+}
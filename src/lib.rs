@@ -1,16 +1,31 @@
+pub mod chunk;
+pub mod cite;
+pub mod condense;
 pub mod embed;
-//pub mod embeddingsdb;
+pub mod embeddingsdb;
+pub mod filter;
+pub mod fts;
+pub mod history;
+pub mod ingest;
+pub mod llm;
+pub mod loader;
+pub mod rank;
+pub mod record;
+pub mod rerank;
 
 pub mod consts {
     use fastembed::EmbeddingModel;
 
     pub const DOCUMENTS_PATH: &str = "./knowledge/2024-02-13_the_rust_book_short.txt";
-    pub const TOKENIZER_MODEL: &str = "bert-base-cased";
-    pub const MAX_TOKENS: usize = 1000;
+    /// Chunk size for embedding models whose maximum sequence length is unknown.
+    pub const MAX_TOKENS: usize = 512;
     pub const EMBEDDING_MODEL: EmbeddingModel = EmbeddingModel::MultilingualE5Small;
-    pub const EMBEDDINGSIZE: i32 = 384;
 
     pub const MODEL: &str = "mistral";
+    /// Default server of the Ollama embedder and chat backend.
+    pub const OLLAMA_BASE_URL: &str = "http://127.0.0.1:11434";
+    /// Default server of the OpenAI-compatible embedder and chat backend, e.g. `llama.cpp`.
+    pub const OPENAI_BASE_URL: &str = "http://localhost:8080/v1";
     pub const SYSTEM_CLOWN: &str = r"
     You are a troll LLM.
 
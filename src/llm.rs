@@ -0,0 +1,385 @@
+use crate::consts::{MODEL, OLLAMA_BASE_URL, OPENAI_BASE_URL};
+use crate::embed::split_host_port;
+use anyhow::{anyhow, Context, Result};
+use async_openai::{
+    config::{Config, OpenAIConfig},
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use ollama_rs::{
+    generation::chat::{request::ChatMessageRequest, MessageRole},
+    Ollama,
+};
+use tracing::{info, instrument};
+
+/// Context window Ollama loads models with unless their Modelfile sets `num_ctx`.
+const OLLAMA_CONTEXT_LENGTH: usize = 2048;
+/// llama.cpp and llamafile serve a single model and ignore its name.
+const OPENAI_MODEL: &str = "LLaMA_CPP";
+
+/// Pieces of an answer in the order the model generates them.
+pub type TokenStream = BoxStream<'static, Result<String>>;
+
+/// Who wrote a [`ChatMessage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::System,
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::User,
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::Assistant,
+            content: content.into(),
+        }
+    }
+}
+
+/// Chats with an LLM, independent of the server it runs on.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    /// Name of the model that answers.
+    fn model(&self) -> &str;
+    /// Generate the reply to `messages` in one piece.
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<String>;
+    /// Generate the reply to `messages`, streamed while it is generated.
+    async fn chat_stream(&self, messages: &[ChatMessage]) -> Result<TokenStream>;
+    /// Models the server can answer with.
+    async fn list_models(&self) -> Result<Vec<String>>;
+    /// Number of tokens the model can attend to, prompt and reply included. `None` if the
+    /// server doesn't tell.
+    async fn context_length(&self) -> Result<Option<usize>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LlmProvider {
+    /// Ollama's `/api/chat` endpoint.
+    Ollama,
+    /// Any server implementing the `OpenAI` `/v1/chat/completions` endpoint, e.g. `llama.cpp`
+    /// or llamafile.
+    Openai,
+}
+
+/// Selects and configures the chat model.
+#[derive(Debug, Clone, clap::Args)]
+pub struct LlmConfig {
+    #[arg(
+        long = "llm-provider",
+        env = "LLM_PROVIDER",
+        value_enum,
+        default_value_t = LlmProvider::Openai
+    )]
+    pub provider: LlmProvider,
+    /// Model name, e.g. `mistral` for Ollama. Every provider has a default.
+    #[arg(long = "llm-model", env = "LLM_MODEL")]
+    pub model: Option<String>,
+    /// Base URL of the Ollama or OpenAI-compatible server.
+    #[arg(long = "llm-base-url", env = "LLM_BASE_URL")]
+    pub base_url: Option<String>,
+    #[arg(
+        long = "llm-api-key",
+        env = "LLM_API_KEY",
+        default_value = "sk-no-key-required",
+        hide_env_values = true
+    )]
+    pub api_key: String,
+    /// Context window of the model in tokens, asked from the server if not set.
+    #[arg(long = "llm-context-length", env = "LLM_CONTEXT_LENGTH")]
+    pub context_length: Option<usize>,
+}
+
+/// Create the chat backend selected by `config`.
+#[instrument]
+pub fn init_llm(config: &LlmConfig) -> Result<Box<dyn LlmBackend>> {
+    let backend: Box<dyn LlmBackend> = match config.provider {
+        LlmProvider::Ollama => Box::new(OllamaBackend::new(
+            config.base_url.as_deref().unwrap_or(OLLAMA_BASE_URL),
+            config.model.as_deref().unwrap_or(MODEL),
+            config.context_length,
+        )?),
+        LlmProvider::Openai => Box::new(OpenAiBackend::new(
+            config.base_url.as_deref().unwrap_or(OPENAI_BASE_URL),
+            config.model.as_deref().unwrap_or(OPENAI_MODEL),
+            &config.api_key,
+            config.context_length,
+        )),
+    };
+    info!(
+        "Chatting with {} via {:?}",
+        backend.model(),
+        config.provider
+    );
+    Ok(backend)
+}
+
+/// Chat with a model served by Ollama.
+pub struct OllamaBackend {
+    ollama: Ollama,
+    model: String,
+    context_length: Option<usize>,
+}
+
+impl OllamaBackend {
+    /// Connects to `base_url`, e.g. `http://127.0.0.1:11434`. `context_length` overrides the
+    /// context window of the model.
+    pub fn new(base_url: &str, model: &str, context_length: Option<usize>) -> Result<Self> {
+        let (host, port) = split_host_port(base_url)?;
+        Ok(OllamaBackend {
+            ollama: Ollama::new(host, port),
+            model: model.to_string(),
+            context_length,
+        })
+    }
+
+    fn request(&self, messages: &[ChatMessage]) -> ChatMessageRequest {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    Role::System => MessageRole::System,
+                    Role::User => MessageRole::User,
+                    Role::Assistant => MessageRole::Assistant,
+                };
+                ollama_rs::generation::chat::ChatMessage::new(role, message.content.clone())
+            })
+            .collect();
+        ChatMessageRequest::new(self.model.clone(), messages)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<String> {
+        let response = self
+            .ollama
+            .send_chat_messages(self.request(messages))
+            .await
+            .map_err(|e| anyhow!("Ollama failed to chat with {}: {e}", self.model))?;
+        Ok(response
+            .message
+            .map(|message| message.content)
+            .unwrap_or_default())
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage]) -> Result<TokenStream> {
+        let model = self.model.clone();
+        let stream = self
+            .ollama
+            .send_chat_messages_stream(self.request(messages))
+            .await
+            .map_err(|e| anyhow!("Ollama failed to chat with {model}: {e}"))?;
+        Ok(stream
+            .map(move |response| {
+                let response = response
+                    .map_err(|()| anyhow!("Ollama failed to stream the reply of {model}"))?;
+                Ok(response
+                    .message
+                    .map(|message| message.content)
+                    .unwrap_or_default())
+            })
+            .boxed())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let models = self
+            .ollama
+            .list_local_models()
+            .await
+            .map_err(|e| anyhow!("Failed to list the models of Ollama: {e}"))?;
+        Ok(models.into_iter().map(|model| model.name).collect())
+    }
+
+    async fn context_length(&self) -> Result<Option<usize>> {
+        if self.context_length.is_some() {
+            return Ok(self.context_length);
+        }
+        let info = self
+            .ollama
+            .show_model_info(self.model.clone())
+            .await
+            .map_err(|e| anyhow!("Failed to get the parameters of {}: {e}", self.model))?;
+        Ok(Some(
+            num_ctx(&info.parameters).unwrap_or(OLLAMA_CONTEXT_LENGTH),
+        ))
+    }
+}
+
+/// The `num_ctx` line of the parameters in a Modelfile.
+fn num_ctx(parameters: &str) -> Option<usize> {
+    parameters.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("num_ctx"), Some(value)) => value.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Chat with a model behind an OpenAI-compatible `/v1/chat/completions` endpoint.
+pub struct OpenAiBackend {
+    client: Client<OpenAIConfig>,
+    model: String,
+    context_length: Option<usize>,
+}
+
+impl OpenAiBackend {
+    /// Connects to `base_url`, e.g. `http://localhost:8080/v1`. `context_length` overrides the
+    /// context window of the model.
+    pub fn new(base_url: &str, model: &str, api_key: &str, context_length: Option<usize>) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_key(api_key)
+            .with_api_base(base_url);
+        OpenAiBackend {
+            client: Client::with_config(config),
+            model: model.to_string(),
+            context_length,
+        }
+    }
+
+    fn request(&self, messages: &[ChatMessage]) -> Result<CreateChatCompletionRequest> {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let content = message.content.clone();
+                Ok(match message.role {
+                    Role::System => ChatCompletionRequestSystemMessageArgs::default()
+                        .content(content)
+                        .build()?
+                        .into(),
+                    Role::User => ChatCompletionRequestUserMessageArgs::default()
+                        .content(content)
+                        .build()?
+                        .into(),
+                    Role::Assistant => ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(content)
+                        .build()?
+                        .into(),
+                })
+            })
+            .collect::<Result<Vec<ChatCompletionRequestMessage>>>()?;
+        CreateChatCompletionRequestArgs::default()
+            .model(&self.model)
+            .n(1)
+            .messages(messages)
+            .build()
+            .context("Failed to build ChatCompletionRequest")
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<String> {
+        let response = self
+            .client
+            .chat()
+            .create(self.request(messages)?)
+            .await
+            .with_context(|| format!("Failed to chat with {}", self.model))?;
+        Ok(response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .unwrap_or_default())
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage]) -> Result<TokenStream> {
+        let stream = self
+            .client
+            .chat()
+            .create_stream(self.request(messages)?)
+            .await
+            .with_context(|| format!("Failed to chat with {}", self.model))?;
+        Ok(stream
+            .map(|response| {
+                let response = response.context("Failed to stream the reply")?;
+                Ok(response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .unwrap_or_default())
+            })
+            .boxed())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let models = self
+            .client
+            .models()
+            .list()
+            .await
+            .context("Failed to list the models of the server")?;
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+
+    /// Only `llama.cpp` and llamafile tell their context window, via the `/props` endpoint.
+    async fn context_length(&self) -> Result<Option<usize>> {
+        if self.context_length.is_some() {
+            return Ok(self.context_length);
+        }
+        let base_url = self.client.config().api_base().trim_end_matches('/');
+        let root = base_url.strip_suffix("/v1").unwrap_or(base_url);
+        let response = reqwest::get(format!("{root}/props"))
+            .await
+            .with_context(|| format!("Failed to reach {root}"))?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        let props: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to read the server properties")?;
+        Ok(props["default_generation_settings"]["n_ctx"]
+            .as_u64()
+            .and_then(|n_ctx| usize::try_from(n_ctx).ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_context_length_from_parameters() {
+        let parameters = "stop \"[INST]\"\nnum_ctx 8192\ntemperature 0.2";
+        assert_eq!(num_ctx(parameters), Some(8192));
+        assert_eq!(num_ctx("stop \"[INST]\""), None);
+    }
+}
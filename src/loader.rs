@@ -0,0 +1,480 @@
+use anyhow::{anyhow, Context, Result};
+use epub::doc::{EpubDoc, NavPoint};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::io::Cursor;
+use std::path::Path;
+
+/// Elements whose content is navigation, scripts or other boilerplate rather than text.
+const SKIPPED_ELEMENTS: [&str; 13] = [
+    "script", "style", "noscript", "template", "svg", "nav", "header", "footer", "aside", "form",
+    "button", "iframe", "head",
+];
+
+/// Elements that start a new paragraph.
+const BLOCK_ELEMENTS: [&str; 16] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "blockquote",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "table",
+    "tr",
+    "figure",
+    "figcaption",
+    "hr",
+];
+
+/// Text extracted from a document file, split into parts that are chunked separately.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadedDocument {
+    /// Title from the document's metadata, if it has one.
+    pub title: Option<String>,
+    pub parts: Vec<LoadedPart>,
+}
+
+/// A page, chapter or the whole text of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedPart {
+    pub text: String,
+    /// Page number starting at 1, for paginated formats.
+    pub page: Option<u32>,
+    /// Title of the part, e.g. the chapter of a book.
+    pub heading: Option<String>,
+}
+
+impl LoadedDocument {
+    fn single(title: Option<String>, text: String) -> Self {
+        LoadedDocument {
+            title,
+            parts: vec![LoadedPart {
+                text,
+                page: None,
+                heading: None,
+            }],
+        }
+    }
+}
+
+/// Extracts the text of one document format. Structure that the chunker understands, like
+/// headings, is kept as Markdown.
+pub trait DocumentLoader: Send + Sync {
+    fn format(&self) -> DocumentFormat;
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentFormat {
+    Text,
+    Markdown,
+    Html,
+    Pdf,
+    Epub,
+}
+
+impl DocumentFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            DocumentFormat::Text => "text",
+            DocumentFormat::Markdown => "markdown",
+            DocumentFormat::Html => "html",
+            DocumentFormat::Pdf => "pdf",
+            DocumentFormat::Epub => "epub",
+        }
+    }
+
+    /// Format of files with `extension`, `None` for extensions without a dedicated loader.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "txt" | "text" => Some(DocumentFormat::Text),
+            "md" | "markdown" => Some(DocumentFormat::Markdown),
+            "html" | "htm" | "xhtml" => Some(DocumentFormat::Html),
+            "pdf" => Some(DocumentFormat::Pdf),
+            "epub" => Some(DocumentFormat::Epub),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the first bytes of a file. Anything that is valid UTF-8 and not
+    /// HTML counts as text.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"%PDF-") {
+            return Some(DocumentFormat::Pdf);
+        }
+        // EPUBs are ZIP archives that store their MIME type uncompressed as first entry
+        if bytes.starts_with(b"PK\x03\x04")
+            && bytes
+                .get(30..58)
+                .is_some_and(|entry| entry == b"mimetypeapplication/epub+zip")
+        {
+            return Some(DocumentFormat::Epub);
+        }
+        let text = std::str::from_utf8(bytes).ok()?;
+        let start = text.trim_start_matches('\u{feff}').trim_start();
+        let start = start.get(..14).unwrap_or(start).to_ascii_lowercase();
+        if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            Some(DocumentFormat::Html)
+        } else {
+            Some(DocumentFormat::Text)
+        }
+    }
+
+    pub fn loader(self) -> Box<dyn DocumentLoader> {
+        match self {
+            DocumentFormat::Text => Box::new(TextLoader),
+            DocumentFormat::Markdown => Box::new(MarkdownLoader),
+            DocumentFormat::Html => Box::new(HtmlLoader),
+            DocumentFormat::Pdf => Box::new(PdfLoader),
+            DocumentFormat::Epub => Box::new(EpubLoader),
+        }
+    }
+}
+
+/// Pick the loader by the extension of `path`, or by sniffing `bytes` if the extension is
+/// unknown.
+pub fn loader_for(path: &Path, bytes: &[u8]) -> Result<Box<dyn DocumentLoader>> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(DocumentFormat::from_extension)
+        .or_else(|| DocumentFormat::sniff(bytes))
+        .map(DocumentFormat::loader)
+        .ok_or_else(|| anyhow!("Unsupported document format of {}", path.display()))
+}
+
+fn utf8(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).context("Document is not valid UTF-8")
+}
+
+pub struct TextLoader;
+
+impl DocumentLoader for TextLoader {
+    fn format(&self) -> DocumentFormat {
+        DocumentFormat::Text
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument> {
+        Ok(LoadedDocument::single(None, utf8(bytes)?))
+    }
+}
+
+/// Markdown is chunked as is, only a YAML front matter block is removed and its `title` used.
+pub struct MarkdownLoader;
+
+impl DocumentLoader for MarkdownLoader {
+    fn format(&self) -> DocumentFormat {
+        DocumentFormat::Markdown
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument> {
+        let text = utf8(bytes)?;
+        let Some((front_matter, body)) = split_front_matter(&text) else {
+            return Ok(LoadedDocument::single(None, text));
+        };
+        let title = front_matter.lines().find_map(|line| {
+            let value = line
+                .strip_prefix("title:")?
+                .trim()
+                .trim_matches(['"', '\'']);
+            (!value.is_empty()).then(|| value.to_string())
+        });
+        Ok(LoadedDocument::single(title, body.to_string()))
+    }
+}
+
+/// Split `---` delimited front matter from the rest of a Markdown document.
+fn split_front_matter(text: &str) -> Option<(&str, &str)> {
+    let rest = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))?;
+    let end = rest.find("\n---")?;
+    let body = rest[end + 4..].trim_start_matches(['-', '\r']);
+    Some((&rest[..end], body.strip_prefix('\n').unwrap_or(body)))
+}
+
+/// Keeps the main content of a page with its headings, dropping navigation, scripts and
+/// other boilerplate.
+pub struct HtmlLoader;
+
+impl DocumentLoader for HtmlLoader {
+    fn format(&self) -> DocumentFormat {
+        DocumentFormat::Html
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument> {
+        let (title, text) = html_to_markdown(&String::from_utf8_lossy(bytes));
+        Ok(LoadedDocument::single(title, text))
+    }
+}
+
+/// Title and text of an HTML page, with headings, lists and preformatted blocks as Markdown.
+/// Only `<main>` or `<article>` is converted if the page has one.
+pub fn html_to_markdown(html: &str) -> (Option<String>, String) {
+    let document = Html::parse_document(html);
+    let selector = |css: &str| Selector::parse(css).expect("Valid selector");
+    let title = document
+        .select(&selector("title"))
+        .next()
+        .map(|title| {
+            collapse_whitespace(&title.text().collect::<String>())
+                .trim()
+                .to_string()
+        })
+        .filter(|title| !title.is_empty());
+    let root = document
+        .select(&selector("main"))
+        .next()
+        .or_else(|| document.select(&selector("article")).next())
+        .unwrap_or_else(|| document.root_element());
+    let mut markdown = String::new();
+    write_markdown(root, &mut markdown);
+    (title, tidy_blank_lines(&markdown))
+}
+
+fn write_markdown(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => {
+                // Whitespace between inline elements separates words, at line starts it is noise
+                let text = collapse_whitespace(text);
+                if out.is_empty() || out.ends_with(['\n', ' ']) {
+                    out.push_str(text.trim_start());
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::Element(_) => {
+                let child = ElementRef::wrap(child).expect("Node is an element");
+                write_element(child, out);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_element(element: ElementRef, out: &mut String) {
+    let name = element.value().name();
+    if SKIPPED_ELEMENTS.contains(&name) {
+        return;
+    }
+    match name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = usize::from(name.as_bytes()[1] - b'0');
+            let heading = collapse_whitespace(&element.text().collect::<String>());
+            if !heading.is_empty() {
+                out.push_str("\n\n");
+                out.push_str(&"#".repeat(level));
+                out.push(' ');
+                out.push_str(heading.trim());
+                out.push_str("\n\n");
+            }
+        }
+        "pre" => {
+            let code: String = element.text().collect();
+            out.push_str("\n\n```\n");
+            out.push_str(code.trim_end());
+            out.push_str("\n```\n\n");
+        }
+        "br" => out.push('\n'),
+        "li" => {
+            if !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str("- ");
+            write_markdown(element, out);
+            out.push('\n');
+        }
+        "td" | "th" => {
+            write_markdown(element, out);
+            out.push(' ');
+        }
+        _ if BLOCK_ELEMENTS.contains(&name) => {
+            out.push_str("\n\n");
+            write_markdown(element, out);
+            out.push_str("\n\n");
+        }
+        _ => write_markdown(element, out),
+    }
+}
+
+/// Replace runs of whitespace by a single space, keeping a leading and trailing one.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    for (i, word) in text.split_whitespace().enumerate() {
+        if i > 0 {
+            collapsed.push(' ');
+        }
+        collapsed.push_str(word);
+    }
+    if collapsed.is_empty() {
+        return collapsed;
+    }
+    if text.starts_with(char::is_whitespace) {
+        collapsed.insert(0, ' ');
+    }
+    if text.ends_with(char::is_whitespace) {
+        collapsed.push(' ');
+    }
+    collapsed
+}
+
+/// Trim every line and leave at most one blank line between paragraphs, except inside code
+/// blocks.
+fn tidy_blank_lines(text: &str) -> String {
+    let mut tidy = String::with_capacity(text.len());
+    let mut in_code = false;
+    let mut blank = true;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        let line = if in_code {
+            line.trim_end()
+        } else {
+            line.trim()
+        };
+        if line.is_empty() && !in_code {
+            if !blank {
+                tidy.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        tidy.push_str(line);
+        tidy.push('\n');
+        blank = false;
+    }
+    tidy.trim_end().to_string()
+}
+
+/// One part per page, numbered from 1.
+pub struct PdfLoader;
+
+impl DocumentLoader for PdfLoader {
+    fn format(&self) -> DocumentFormat {
+        DocumentFormat::Pdf
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument> {
+        let pdf = lopdf::Document::load_mem(bytes).context("Failed to parse PDF")?;
+        anyhow::ensure!(!pdf.is_encrypted(), "Encrypted PDFs are not supported");
+        let title = pdf
+            .trailer
+            .get(b"Info")
+            .and_then(|info| pdf.dereference(info))
+            .and_then(|(_, info)| info.as_dict())
+            .and_then(|info| info.get(b"Title"))
+            .and_then(lopdf::decode_text_string)
+            .ok()
+            .map(|title| title.trim().to_string())
+            .filter(|title| !title.is_empty());
+        let mut parts = Vec::new();
+        for page in pdf.get_pages().into_keys() {
+            let text = pdf
+                .extract_text(&[page])
+                .with_context(|| format!("Failed to extract the text of page {page}"))?;
+            parts.push(LoadedPart {
+                text: tidy_blank_lines(&text),
+                page: Some(page),
+                heading: None,
+            });
+        }
+        Ok(LoadedDocument { title, parts })
+    }
+}
+
+/// One part per chapter in reading order, named after its entry in the table of contents.
+pub struct EpubLoader;
+
+impl DocumentLoader for EpubLoader {
+    fn format(&self) -> DocumentFormat {
+        DocumentFormat::Epub
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<LoadedDocument> {
+        let mut book = EpubDoc::from_reader(Cursor::new(bytes.to_vec()))
+            .map_err(|e| anyhow!("Failed to open EPUB: {e}"))?;
+        let title = book.get_title();
+        let mut parts = Vec::new();
+        for item in book.spine.clone().into_iter().filter(|item| item.linear) {
+            let Some(path) = book.resources.get(&item.idref).map(|r| r.path.clone()) else {
+                continue;
+            };
+            let Some((xhtml, _)) = book.get_resource_str(&item.idref) else {
+                continue;
+            };
+            let (_, text) = html_to_markdown(&xhtml);
+            if text.is_empty() {
+                continue;
+            }
+            parts.push(LoadedPart {
+                text,
+                page: None,
+                heading: toc_label(&book.toc, &path),
+            });
+        }
+        Ok(LoadedDocument { title, parts })
+    }
+}
+
+/// Label of the first table of contents entry pointing into the file at `path`.
+fn toc_label(toc: &[NavPoint], path: &Path) -> Option<String> {
+    toc.iter().find_map(|point| {
+        let content = point.content.to_string_lossy();
+        let file = content.split('#').next().unwrap_or_default();
+        if Path::new(file) == path {
+            Some(point.label.trim().to_string())
+        } else {
+            toc_label(&point.children, path)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_keep_headings_and_drop_boilerplate() {
+        let html = "<!DOCTYPE html><html><head><title>Hash Maps</title>\
+                    <script>track()</script></head><body><nav><a href='/'>Home</a></nav>\
+                    <main><h1>Storing Keys</h1><p>Hash maps   store\n<em>keys</em> and values.</p>\
+                    <ul><li>insert</li><li>get</li></ul><pre>let x = 1;\n  x + 1</pre></main>\
+                    <footer>(c) 2024</footer></body></html>";
+        let (title, text) = html_to_markdown(html);
+        assert_eq!(title.as_deref(), Some("Hash Maps"));
+        assert_eq!(
+            text,
+            "# Storing Keys\n\nHash maps store keys and values.\n\n- insert\n- get\n\n\
+             ```\nlet x = 1;\n  x + 1\n```"
+        );
+    }
+
+    #[test]
+    fn should_pick_loader_by_extension_or_content() {
+        let format =
+            |path: &str, bytes: &[u8]| loader_for(Path::new(path), bytes).unwrap().format();
+        assert_eq!(format("ch08.md", b"# Ch. 8"), DocumentFormat::Markdown);
+        assert_eq!(format("ch08.HTM", b""), DocumentFormat::Html);
+        assert_eq!(format("book", b"%PDF-1.7\n"), DocumentFormat::Pdf);
+        assert_eq!(
+            format("export", b"  <!doctype HTML><html></html>"),
+            DocumentFormat::Html
+        );
+        assert_eq!(format("notes", b"plain"), DocumentFormat::Text);
+        assert!(loader_for(Path::new("image"), &[0xff, 0xd8, 0xff]).is_err());
+    }
+
+    #[test]
+    fn should_strip_markdown_front_matter() {
+        let loaded = MarkdownLoader
+            .load(b"---\ntitle: \"Common Collections\"\ntags: [book]\n---\n# Vectors\n")
+            .unwrap();
+        assert_eq!(loaded.title.as_deref(), Some("Common Collections"));
+        assert_eq!(loaded.parts[0].text, "# Vectors\n");
+    }
+}
@@ -0,0 +1,168 @@
+use crate::embeddingsdb::SearchHit;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// Rank constant from the original RRF paper, damps the influence of the top ranks.
+pub const RRF_K: f32 = 60.0;
+
+/// Weighted reciprocal rank fusion. Every ranking is a list of ids, best first, with the weight
+/// of that ranking. An id scores `weight / (RRF_K + rank)` summed over all rankings it appears
+/// in. Returns all ids with their fused score, best first.
+pub fn reciprocal_rank_fusion(rankings: &[(&[i32], f32)]) -> Vec<(i32, f32)> {
+    let mut scores: HashMap<i32, f32> = HashMap::new();
+    for (ranking, weight) in rankings {
+        for (rank, id) in ranking.iter().enumerate() {
+            #[allow(clippy::cast_precision_loss)]
+            let rank = (rank + 1) as f32;
+            *scores.entry(*id).or_default() += weight / (RRF_K + rank);
+        }
+    }
+    let mut fused: Vec<(i32, f32)> = scores.into_iter().collect();
+    // Ties are broken by id to keep the order deterministic
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    fused
+}
+
+/// Enables maximal marginal relevance.
+#[derive(Debug, Clone, clap::Args)]
+pub struct MmrConfig {
+    /// Diversify the retrieved chunks with maximal marginal relevance. 1 ranks by relevance
+    /// only, 0 by diversity only. MMR is off if not set.
+    #[arg(long, env = "MMR_LAMBDA", value_parser = parse_lambda)]
+    pub mmr_lambda: Option<f32>,
+    /// Number of candidates MMR selects the `k` chunks from.
+    #[arg(long, env = "MMR_FETCH_K", default_value_t = 20)]
+    pub mmr_fetch_k: usize,
+}
+
+fn parse_lambda(s: &str) -> Result<f32> {
+    let lambda: f32 = s
+        .parse()
+        .with_context(|| format!("Expected a number, got '{s}'"))?;
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&lambda),
+        "Lambda must be between 0 and 1, got {lambda}"
+    );
+    Ok(lambda)
+}
+
+/// Greedily pick `k` items that are relevant but unlike the ones picked before. Each step takes
+/// the item maximizing `lambda * relevance - (1 - lambda) * max_similarity_to_picked`. Returns
+/// indices into `relevance` and `embeddings`, in the order they were picked.
+pub fn maximal_marginal_relevance(
+    relevance: &[f32],
+    embeddings: &[&[f32]],
+    lambda: f32,
+    k: usize,
+) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(k.min(relevance.len()));
+    // Highest similarity of every candidate to any selected item so far
+    let mut redundancy = vec![f32::NEG_INFINITY; relevance.len()];
+    while selected.len() < k.min(relevance.len()) {
+        let best = (0..relevance.len())
+            .filter(|i| !selected.contains(i))
+            .map(|i| {
+                let penalty = if selected.is_empty() {
+                    0.0
+                } else {
+                    redundancy[i]
+                };
+                (i, lambda * relevance[i] - (1.0 - lambda) * penalty)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(i, _)| i);
+        let Some(best) = best else { break };
+        selected.push(best);
+        for (i, max_similarity) in redundancy.iter_mut().enumerate() {
+            *max_similarity =
+                max_similarity.max(cosine_similarity(embeddings[i], embeddings[best]));
+        }
+    }
+    selected
+}
+
+/// Reorder and cut `hits` down to `k` with [`maximal_marginal_relevance`], using the stored
+/// embeddings for redundancy. Relevance is the rerank score if the hits were reranked, else
+/// the retrieval score, scaled to 0..1 so that it weighs like the similarities.
+pub fn diversify(hits: Vec<SearchHit>, lambda: f32, k: usize) -> Vec<SearchHit> {
+    let scores: Vec<f32> = hits
+        .iter()
+        .map(|hit| hit.rerank_score.unwrap_or(hit.score))
+        .collect();
+    let relevance = min_max_normalize(&scores);
+    let embeddings: Vec<&[f32]> = hits.iter().map(|hit| hit.embedding.as_slice()).collect();
+    let order = maximal_marginal_relevance(&relevance, &embeddings, lambda, k);
+    let mut hits: Vec<Option<SearchHit>> = hits.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| hits[i].take()).collect()
+}
+
+/// Scale `scores` linearly to 0..1. All scores are 1 if they are equal.
+fn min_max_normalize(scores: &[f32]) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    scores
+        .iter()
+        .map(|score| {
+            if max > min {
+                (score - min) / (max - min)
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_prefer_ids_ranked_high_by_both() {
+        let dense = [1, 2, 3];
+        let keyword = [3, 4, 1];
+        let fused = reciprocal_rank_fusion(&[(&dense, 1.0), (&keyword, 1.0)]);
+        let ids: Vec<i32> = fused.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 3, 2, 4]);
+
+        let fused = reciprocal_rank_fusion(&[(&dense, 0.0), (&keyword, 1.0)]);
+        assert_eq!(fused[0].0, 3);
+    }
+
+    #[test]
+    fn should_skip_near_duplicates() {
+        let relevance = [0.9, 0.89, 0.5];
+        let embeddings: [&[f32]; 3] = [&[1.0, 0.0], &[0.99, 0.1], &[0.0, 1.0]];
+        assert_eq!(
+            maximal_marginal_relevance(&relevance, &embeddings, 0.5, 2),
+            vec![0, 2]
+        );
+        assert_eq!(
+            maximal_marginal_relevance(&relevance, &embeddings, 1.0, 2),
+            vec![0, 1]
+        );
+        assert_eq!(
+            maximal_marginal_relevance(&relevance, &embeddings, 0.5, 5).len(),
+            3
+        );
+    }
+
+    #[test]
+    fn should_normalize_relevance_and_check_lambda() {
+        assert_eq!(min_max_normalize(&[2.0, -4.0, 0.5]), vec![1.0, 0.0, 0.75]);
+        assert_eq!(min_max_normalize(&[0.3, 0.3]), vec![1.0, 1.0]);
+        assert!(parse_lambda("0.5").is_ok());
+        assert!(parse_lambda("1.5").is_err());
+        assert!(parse_lambda("-0.1").is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::{anyhow, Context, Result};
+use arrow_array::{
+    types::Float32Type, Array, ArrayRef, FixedSizeListArray, Float32Array, Int32Array, RecordBatch,
+    StringArray, TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Where a chunk came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkMetadata {
+    /// Path of the source document as it was passed to the ingestion.
+    pub source: String,
+    pub title: String,
+    /// Headings enclosing the chunk joined with ` > `, e.g. `Ch. 8 > Storing Keys with Hash
+    /// Maps`. Empty if the document has no structure.
+    pub heading_path: String,
+    /// Byte range of the chunk in the text extracted from the document.
+    pub byte_start: u64,
+    pub byte_end: u64,
+    /// Character range of the chunk in the text extracted from the document.
+    pub char_start: u64,
+    pub char_end: u64,
+    /// Position of the chunk within its document, starting at 0.
+    pub chunk_index: u32,
+    /// Number of bytes at the start of the chunk that repeat the end of the previous chunk.
+    pub overlap_bytes: u64,
+    /// Page of the chunk, starting at 1, for paginated formats like PDF.
+    pub page: Option<u32>,
+    /// Hex encoded SHA-256 of the whole source document.
+    pub doc_hash: String,
+    /// Hex encoded SHA-256 of the chunk text.
+    pub chunk_hash: String,
+    pub modified_at: DateTime<Utc>,
+    pub ingested_at: DateTime<Utc>,
+    /// User supplied key/value pairs, stored in one nullable string column per key.
+    pub extra: BTreeMap<String, String>,
+}
+
+/// A row of the embeddings table, without its embedding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub id: i32,
+    pub text: String,
+    pub metadata: ChunkMetadata,
+}
+
+#[cfg(test)]
+impl ChunkMetadata {
+    /// Metadata of the `chunk_index`th chunk of `source` with everything else empty.
+    pub fn for_test(source: &str, chunk_index: u32) -> Self {
+        ChunkMetadata {
+            source: source.to_string(),
+            title: String::new(),
+            heading_path: String::new(),
+            byte_start: 0,
+            byte_end: 0,
+            char_start: 0,
+            char_end: 0,
+            chunk_index,
+            overlap_bytes: 0,
+            page: None,
+            doc_hash: "d0c".to_string(),
+            chunk_hash: String::new(),
+            modified_at: DateTime::UNIX_EPOCH,
+            ingested_at: DateTime::UNIX_EPOCH,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+impl Chunk {
+    /// Whether `next` is the chunk directly after this one in the same version of a document.
+    pub fn is_followed_by(&self, next: &Chunk) -> bool {
+        self.metadata.source == next.metadata.source
+            && self.metadata.doc_hash == next.metadata.doc_hash
+            && self.metadata.chunk_index + 1 == next.metadata.chunk_index
+    }
+
+    /// Append the chunk following this one, dropping the text both have in common.
+    fn append(&mut self, next: &Chunk) {
+        let overlap = usize::try_from(next.metadata.overlap_bytes).unwrap_or(usize::MAX);
+        match next.text.get(overlap..) {
+            Some(rest) if overlap > 0 => self.text.push_str(rest),
+            _ => {
+                self.text.push('\n');
+                self.text.push_str(&next.text);
+            }
+        }
+        self.metadata.byte_end = next.metadata.byte_end;
+        self.metadata.char_end = next.metadata.char_end;
+        self.metadata.chunk_hash = String::new();
+    }
+}
+
+/// Merge runs of adjacent chunks of a document into one chunk each, so that text repeated by
+/// the chunk overlap shows up once. A merged chunk keeps the id and position of its
+/// first chunk and takes the place of its best ranked one. The chunk hash of merged chunks
+/// is cleared, it no longer matches their text.
+pub fn stitch_adjacent(chunks: Vec<Chunk>) -> Vec<Chunk> {
+    let mut ranked: Vec<(usize, Chunk)> = chunks.into_iter().enumerate().collect();
+    ranked.sort_by(|(_, a), (_, b)| {
+        (
+            &a.metadata.source,
+            &a.metadata.doc_hash,
+            a.metadata.chunk_index,
+        )
+            .cmp(&(
+                &b.metadata.source,
+                &b.metadata.doc_hash,
+                b.metadata.chunk_index,
+            ))
+    });
+    let mut runs: Vec<(usize, Chunk, Chunk)> = Vec::new();
+    for (rank, chunk) in ranked {
+        match runs.last_mut() {
+            Some((best_rank, merged, last)) if last.is_followed_by(&chunk) => {
+                *best_rank = (*best_rank).min(rank);
+                merged.append(&chunk);
+                *last = chunk;
+            }
+            _ => runs.push((rank, chunk.clone(), chunk)),
+        }
+    }
+    runs.sort_by_key(|(rank, _, _)| *rank);
+    runs.into_iter().map(|(_, merged, _)| merged).collect()
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+/// Schema of the embeddings table with one string column for every key in `extra_columns`.
+pub fn embeddings_schema(embedding_size: i32, extra_columns: &[String]) -> Schema {
+    let mut fields = base_fields(embedding_size);
+    fields.extend(
+        extra_columns
+            .iter()
+            .map(|name| Field::new(name, DataType::Utf8, true)),
+    );
+    Schema::new(fields)
+}
+
+fn base_fields(embedding_size: i32) -> Vec<Field> {
+    vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("text", DataType::Utf8, true),
+        Field::new(
+            "embedding",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                embedding_size,
+            ),
+            true,
+        ),
+        Field::new("source", DataType::Utf8, false),
+        Field::new("title", DataType::Utf8, true),
+        Field::new("heading_path", DataType::Utf8, false),
+        Field::new("byte_start", DataType::UInt64, false),
+        Field::new("byte_end", DataType::UInt64, false),
+        Field::new("char_start", DataType::UInt64, false),
+        Field::new("char_end", DataType::UInt64, false),
+        Field::new("chunk_index", DataType::UInt32, false),
+        Field::new("overlap_bytes", DataType::UInt64, false),
+        Field::new("page", DataType::UInt32, true),
+        Field::new("doc_hash", DataType::Utf8, false),
+        Field::new("chunk_hash", DataType::Utf8, false),
+        Field::new("modified_at", timestamp_type(), true),
+        Field::new("ingested_at", timestamp_type(), false),
+    ]
+}
+
+/// Names of the user supplied metadata columns of a table.
+pub fn extra_columns(schema: &Schema) -> Vec<String> {
+    let base = base_fields(1);
+    schema
+        .fields()
+        .iter()
+        .map(|field| field.name())
+        .filter(|name| !name.starts_with('_') && !base.iter().any(|f| f.name() == *name))
+        .cloned()
+        .collect()
+}
+
+/// Metadata keys become column names and appear in filters, so they must be plain identifiers
+/// that don't shadow a built-in column.
+pub fn check_metadata_key(key: &str) -> Result<()> {
+    let is_identifier = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    anyhow::ensure!(
+        is_identifier,
+        "Metadata key '{key}' must start with a letter and only contain letters, digits and _"
+    );
+    anyhow::ensure!(
+        !base_fields(1).iter().any(|field| field.name() == key),
+        "Metadata key '{key}' is a built-in column"
+    );
+    Ok(())
+}
+
+/// Build a `RecordBatch` matching [`embeddings_schema`] from chunks and their embeddings.
+pub fn to_record_batch(
+    schema: Arc<Schema>,
+    chunks: &[Chunk],
+    embeddings: Vec<Vec<f32>>,
+    embedding_size: i32,
+) -> Result<RecordBatch> {
+    anyhow::ensure!(
+        chunks.len() == embeddings.len(),
+        "Got {} chunks but {} embeddings",
+        chunks.len(),
+        embeddings.len()
+    );
+    let meta = || chunks.iter().map(|c| &c.metadata);
+    let timestamps = |f: fn(&ChunkMetadata) -> DateTime<Utc>| {
+        TimestampMillisecondArray::from_iter_values(meta().map(|m| f(m).timestamp_millis()))
+            .with_timezone("UTC")
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int32Array::from_iter_values(chunks.iter().map(|c| c.id))),
+        Arc::new(StringArray::from_iter_values(
+            chunks.iter().map(|c| c.text.as_str()),
+        )),
+        Arc::new(
+            FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+                embeddings
+                    .into_iter()
+                    .map(|inner_vec| Some(inner_vec.into_iter().map(Some))),
+                embedding_size,
+            ),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            meta().map(|m| m.source.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            meta().map(|m| m.title.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            meta().map(|m| m.heading_path.as_str()),
+        )),
+        Arc::new(UInt64Array::from_iter_values(meta().map(|m| m.byte_start))),
+        Arc::new(UInt64Array::from_iter_values(meta().map(|m| m.byte_end))),
+        Arc::new(UInt64Array::from_iter_values(meta().map(|m| m.char_start))),
+        Arc::new(UInt64Array::from_iter_values(meta().map(|m| m.char_end))),
+        Arc::new(UInt32Array::from_iter_values(meta().map(|m| m.chunk_index))),
+        Arc::new(UInt64Array::from_iter_values(
+            meta().map(|m| m.overlap_bytes),
+        )),
+        Arc::new(meta().map(|m| m.page).collect::<UInt32Array>()),
+        Arc::new(StringArray::from_iter_values(
+            meta().map(|m| m.doc_hash.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            meta().map(|m| m.chunk_hash.as_str()),
+        )),
+        Arc::new(timestamps(|m| m.modified_at)),
+        Arc::new(timestamps(|m| m.ingested_at)),
+    ];
+    let extra = extra_columns(&schema).into_iter().map(|name| {
+        Arc::new(
+            meta()
+                .map(|m| m.extra.get(&name).map(String::as_str))
+                .collect::<StringArray>(),
+        ) as ArrayRef
+    });
+    let columns = columns.into_iter().chain(extra).collect();
+    RecordBatch::try_new(schema, columns).context("Creating RecordBatch failed")
+}
+
+/// Quote a string so that it can be used as literal in a `LanceDB` SQL filter.
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+pub fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| anyhow!("Column '{name}' is missing"))?
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| anyhow!("Column '{name}' has an unexpected type"))
+}
+
+fn datetime(millis: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(millis).ok_or_else(|| anyhow!("Invalid timestamp {millis}"))
+}
+
+/// Read the chunks and their provenance back from a query result.
+pub fn chunks_from_batch(batch: &RecordBatch) -> Result<Vec<Chunk>> {
+    let ids = column::<Int32Array>(batch, "id")?;
+    let texts = column::<StringArray>(batch, "text")?;
+    let sources = column::<StringArray>(batch, "source")?;
+    let titles = column::<StringArray>(batch, "title")?;
+    let heading_paths = column::<StringArray>(batch, "heading_path")?;
+    let byte_starts = column::<UInt64Array>(batch, "byte_start")?;
+    let byte_ends = column::<UInt64Array>(batch, "byte_end")?;
+    let char_starts = column::<UInt64Array>(batch, "char_start")?;
+    let char_ends = column::<UInt64Array>(batch, "char_end")?;
+    let chunk_indices = column::<UInt32Array>(batch, "chunk_index")?;
+    let overlaps = column::<UInt64Array>(batch, "overlap_bytes")?;
+    let pages = column::<UInt32Array>(batch, "page")?;
+    let doc_hashes = column::<StringArray>(batch, "doc_hash")?;
+    let chunk_hashes = column::<StringArray>(batch, "chunk_hash")?;
+    let modified = column::<TimestampMillisecondArray>(batch, "modified_at")?;
+    let ingested = column::<TimestampMillisecondArray>(batch, "ingested_at")?;
+    let extra = extra_columns(&batch.schema())
+        .into_iter()
+        .map(|name| Ok((column::<StringArray>(batch, &name)?, name)))
+        .collect::<Result<Vec<_>>>()?;
+
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Chunk {
+                id: ids.value(i),
+                text: texts.value(i).to_string(),
+                metadata: ChunkMetadata {
+                    source: sources.value(i).to_string(),
+                    title: titles.value(i).to_string(),
+                    heading_path: heading_paths.value(i).to_string(),
+                    byte_start: byte_starts.value(i),
+                    byte_end: byte_ends.value(i),
+                    char_start: char_starts.value(i),
+                    char_end: char_ends.value(i),
+                    chunk_index: chunk_indices.value(i),
+                    overlap_bytes: overlaps.value(i),
+                    page: pages.is_valid(i).then(|| pages.value(i)),
+                    doc_hash: doc_hashes.value(i).to_string(),
+                    chunk_hash: chunk_hashes.value(i).to_string(),
+                    modified_at: if modified.is_null(i) {
+                        DateTime::UNIX_EPOCH
+                    } else {
+                        datetime(modified.value(i))?
+                    },
+                    ingested_at: datetime(ingested.value(i))?,
+                    extra: extra
+                        .iter()
+                        .filter(|(values, _)| values.is_valid(i))
+                        .map(|(values, name)| (name.clone(), values.value(i).to_string()))
+                        .collect(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Read the `embedding` column back into plain vectors.
+pub fn embeddings_from_batch(batch: &RecordBatch) -> Result<Vec<Vec<f32>>> {
+    let embeddings = column::<FixedSizeListArray>(batch, "embedding")?;
+    (0..embeddings.len())
+        .map(|i| {
+            let values = embeddings.value(i);
+            let values = values
+                .as_any()
+                .downcast_ref::<Float32Array>()
+                .ok_or_else(|| anyhow!("Embeddings are expected to be f32"))?;
+            Ok(values.values().to_vec())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_roundtrip_chunks_through_record_batch() {
+        let now = DateTime::from_timestamp_millis(1_707_800_000_000).unwrap();
+        let chunk = Chunk {
+            id: 7,
+            text: "Hash maps store keys and values.".to_string(),
+            metadata: ChunkMetadata {
+                title: "The Rust Programming Language".to_string(),
+                heading_path: "Ch. 8 > Storing Keys with Hash Maps".to_string(),
+                byte_start: 10,
+                byte_end: 42,
+                char_start: 9,
+                char_end: 41,
+                overlap_bytes: 5,
+                page: Some(12),
+                chunk_hash: "c4a".to_string(),
+                modified_at: now,
+                ingested_at: now,
+                extra: BTreeMap::from([("lang".to_string(), "en".to_string())]),
+                ..ChunkMetadata::for_test("knowledge/book.txt", 3)
+            },
+        };
+        let schema = Arc::new(embeddings_schema(
+            2,
+            &["lang".to_string(), "tag".to_string()],
+        ));
+        let batch = to_record_batch(
+            schema,
+            std::slice::from_ref(&chunk),
+            vec![vec![0.1, 0.2]],
+            2,
+        )
+        .unwrap();
+        assert_eq!(chunks_from_batch(&batch).unwrap(), vec![chunk]);
+        assert_eq!(embeddings_from_batch(&batch).unwrap(), vec![vec![0.1, 0.2]]);
+    }
+
+    #[test]
+    fn should_stitch_adjacent_chunks() {
+        let chunk = |id: i32, text: &str, chunk_index: u32, overlap_bytes: u64| Chunk {
+            id,
+            text: text.to_string(),
+            metadata: ChunkMetadata {
+                overlap_bytes,
+                ..ChunkMetadata::for_test("book.txt", chunk_index)
+            },
+        };
+        let chunks = vec![
+            chunk(9, "Vectors.", 5, 0),
+            chunk(2, "values. Keys are hashed.", 2, 7),
+            chunk(1, "Maps store keys and values.", 1, 0),
+            chunk(3, "Updating a map.", 3, 0),
+        ];
+        let texts: Vec<String> = stitch_adjacent(chunks)
+            .into_iter()
+            .map(|chunk| chunk.text)
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "Vectors.",
+                "Maps store keys and values. Keys are hashed.\nUpdating a map."
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_metadata_keys() {
+        assert!(check_metadata_key("lang").is_ok());
+        assert!(check_metadata_key("source").is_err());
+        assert!(check_metadata_key("1st").is_err());
+        assert!(check_metadata_key("x; DROP").is_err());
+    }
+}
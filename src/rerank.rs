@@ -0,0 +1,211 @@
+use crate::embed::ModelCache;
+use crate::embeddingsdb::SearchHit;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use fastembed::{
+    RerankInitOptions, RerankInitOptionsUserDefined, RerankerModel, TextRerank,
+    UserDefinedRerankingModel,
+};
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+/// Scores how well documents answer a query by looking at both together, which is slower but
+/// more precise than comparing embeddings.
+#[async_trait]
+pub trait Reranker: Send + Sync {
+    /// One relevance score per document in input order, higher is more relevant.
+    async fn score(&self, query: &str, documents: Vec<String>) -> Result<Vec<f32>>;
+    fn model_id(&self) -> &str;
+}
+
+/// Enables reranking of the retrieved chunks.
+#[derive(Debug, Clone, clap::Args)]
+pub struct RerankerConfig {
+    /// Cross-encoder used to rerank the retrieved chunks, e.g. `BAAI/bge-reranker-base`.
+    /// Reranking is off if not set.
+    #[arg(long = "reranker", env = "RERANKER_MODEL")]
+    pub model: Option<String>,
+    /// Retrieve this many times `k` candidates for the reranker to choose from.
+    #[arg(long, env = "RERANK_FETCH_FACTOR", default_value_t = 5)]
+    pub rerank_fetch_factor: usize,
+}
+
+/// Create the configured reranker, `None` if reranking is disabled. The model is loaded from
+/// `cache` like the fastembed embedding models.
+#[instrument]
+pub fn init_reranker(
+    config: &RerankerConfig,
+    cache: &ModelCache,
+) -> Result<Option<Box<dyn Reranker>>> {
+    let Some(name) = &config.model else {
+        return Ok(None);
+    };
+    let reranker = FastEmbedReranker::new(parse_reranker_model(name)?, cache)?;
+    info!("Reranking with {}", reranker.model_id());
+    Ok(Some(Box::new(reranker)))
+}
+
+/// Accepts the `HuggingFace` model code or the fastembed enum name, ignoring case.
+fn parse_reranker_model(name: &str) -> Result<RerankerModel> {
+    let supported = TextRerank::list_supported_models();
+    supported
+        .iter()
+        .find(|info| {
+            info.model_code.eq_ignore_ascii_case(name)
+                || format!("{:?}", info.model).eq_ignore_ascii_case(name)
+        })
+        .map(|info| info.model.clone())
+        .ok_or_else(|| {
+            let codes: Vec<_> = supported
+                .iter()
+                .map(|info| info.model_code.as_str())
+                .collect();
+            anyhow!("Unknown reranker model '{name}', supported are: {codes:?}")
+        })
+}
+
+/// Local ONNX cross-encoder run by fastembed.
+pub struct FastEmbedReranker {
+    model: Arc<TextRerank>,
+    model_id: String,
+}
+
+impl FastEmbedReranker {
+    pub fn new(model: RerankerModel, cache: &ModelCache) -> Result<Self> {
+        let info = TextRerank::get_model_info(&model);
+        let model_id = info.model_code;
+        let model = if cache.offline {
+            let files = cache.read_model(&model_id, &info.model_file)?;
+            TextRerank::try_new_from_user_defined(
+                UserDefinedRerankingModel {
+                    onnx_file: files.onnx_file,
+                    tokenizer_files: files.tokenizer_files,
+                },
+                RerankInitOptionsUserDefined::default(),
+            )
+        } else {
+            TextRerank::try_new(RerankInitOptions {
+                model_name: model,
+                cache_dir: cache.dir.clone(),
+                show_download_progress: true,
+                ..Default::default()
+            })
+        }
+        .with_context(|| format!("Failed to initialize reranker {model_id}"))?;
+        Ok(FastEmbedReranker {
+            model: Arc::new(model),
+            model_id,
+        })
+    }
+}
+
+#[async_trait]
+impl Reranker for FastEmbedReranker {
+    async fn score(&self, query: &str, documents: Vec<String>) -> Result<Vec<f32>> {
+        let model = self.model.clone();
+        let query = query.to_string();
+        let n_documents = documents.len();
+        let results = tokio::task::spawn_blocking(move || {
+            let documents: Vec<&str> = documents.iter().map(String::as_str).collect();
+            model.rerank(query.as_str(), documents, false, None)
+        })
+        .await??;
+        // Results come sorted by score, restore the input order
+        let mut scores = vec![f32::NEG_INFINITY; n_documents];
+        for result in results {
+            let score = scores.get_mut(result.index).ok_or_else(|| {
+                anyhow!(
+                    "{} scored document {} of {n_documents}",
+                    self.model_id,
+                    result.index
+                )
+            })?;
+            *score = result.score;
+        }
+        Ok(scores)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+}
+
+/// Order `hits` by their rerank score and keep the best `k`. The retrieval score stays in
+/// [`SearchHit::score`].
+pub async fn rerank(
+    reranker: &dyn Reranker,
+    query: &str,
+    hits: Vec<SearchHit>,
+    k: usize,
+) -> Result<Vec<SearchHit>> {
+    if hits.is_empty() {
+        return Ok(hits);
+    }
+    let texts = hits.iter().map(|hit| hit.chunk.text.clone()).collect();
+    let scores = reranker.score(query, texts).await?;
+    anyhow::ensure!(
+        scores.len() == hits.len(),
+        "{} returned {} scores for {} documents",
+        reranker.model_id(),
+        scores.len(),
+        hits.len()
+    );
+    let mut ranked: Vec<(SearchHit, f32)> = hits.into_iter().zip(scores).collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(ranked
+        .into_iter()
+        .take(k)
+        .map(|(mut hit, score)| {
+            hit.rerank_score = Some(score);
+            hit
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{Chunk, ChunkMetadata};
+
+    /// Scores documents by their length.
+    struct LengthReranker;
+
+    #[async_trait]
+    impl Reranker for LengthReranker {
+        async fn score(&self, _query: &str, documents: Vec<String>) -> Result<Vec<f32>> {
+            #[allow(clippy::cast_precision_loss)]
+            Ok(documents.iter().map(|text| text.len() as f32).collect())
+        }
+
+        fn model_id(&self) -> &'static str {
+            "length"
+        }
+    }
+
+    fn hit(id: i32, text: &str) -> SearchHit {
+        SearchHit {
+            chunk: Chunk {
+                id,
+                text: text.to_string(),
+                metadata: ChunkMetadata::for_test("book.txt", 0),
+            },
+            distance: 0.5,
+            keyword_score: None,
+            score: 0.5,
+            similarity: 0.5,
+            rerank_score: None,
+            embedding: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_best_k_by_rerank_score() {
+        let hits = vec![hit(1, "a"), hit(2, "abc"), hit(3, "ab")];
+        let reranked = rerank(&LengthReranker, "query", hits, 2).await.unwrap();
+        let ranked: Vec<(i32, Option<f32>, f32)> = reranked
+            .iter()
+            .map(|hit| (hit.chunk.id, hit.rerank_score, hit.score))
+            .collect();
+        assert_eq!(ranked, vec![(2, Some(3.0), 0.5), (3, Some(2.0), 0.5)]);
+    }
+}